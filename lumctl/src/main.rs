@@ -1,15 +1,25 @@
 use nix::unistd;
-use std::io::Write;
+use serde_json::{Value, json};
+use std::io::{BufRead, BufReader, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::os::unix::net::UnixStream;
 use std::path::PathBuf;
+use std::time::Duration;
 use std::{env, error::Error, fmt, fs, process};
 
+// Version of lumd's socket protocol this client speaks
+const PROTOCOL_VERSION: u64 = 1;
+
+// How long to wait for lumd to answer a request
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Debug)]
 enum LumctlError {
     Io(std::io::Error),
     Connection(String),
     Usage(String),
+    // Error code and message from a lumd reply
+    Daemon(String, String),
 }
 
 impl fmt::Display for LumctlError {
@@ -18,6 +28,7 @@ impl fmt::Display for LumctlError {
             LumctlError::Io(e) => write!(f, "IO error: {}", e),
             LumctlError::Connection(s) => write!(f, "Failed to connect to lumd: {}", s),
             LumctlError::Usage(s) => write!(f, "Usage error: {}", s),
+            LumctlError::Daemon(code, message) => write!(f, "lumd: {} ({})", message, code),
         }
     }
 }
@@ -32,33 +43,181 @@ impl From<std::io::Error> for LumctlError {
 
 type Result<T> = std::result::Result<T, LumctlError>;
 
-// Simple command enum for our supported commands
+// Brightness or step size as given on the command line
+#[derive(Debug, Clone, Copy)]
+enum Amount {
+    Raw(i64),
+    Percent(f64),
+}
+
+impl Amount {
+    // "5%" is a percentage of the maximum brightness, a bare number raw units
+    fn parse(word: &str) -> Result<Self> {
+        let invalid = || LumctlError::Usage(format!("Invalid amount: {}", word));
+        match word.strip_suffix('%') {
+            Some(percent) => percent
+                .parse()
+                .ok()
+                .filter(|p: &f64| p.is_finite())
+                .map(Amount::Percent)
+                .ok_or_else(invalid),
+            None => word.parse().map(Amount::Raw).map_err(|_| invalid()),
+        }
+    }
+
+    fn args(&self, instant: bool) -> Value {
+        match self {
+            Amount::Raw(raw) => json!({ "raw": raw, "instant": instant }),
+            Amount::Percent(percent) => json!({ "percent": percent, "instant": instant }),
+        }
+    }
+}
+
+// Simple command enum for our supported commands. The bools on brightness
+// changes are false when they should fade instead of jumping.
 enum Command {
-    Up,
-    Down,
+    Up(Option<Amount>, bool),
+    Down(Option<Amount>, bool),
+    Set(Amount, bool),
+    Offset(i64, bool),
     Resample,
+    // Print lumd's status, as JSON when set
+    Status(bool),
+    // Print events as they happen, only the named kinds when given, as JSON when set
+    Watch(Vec<String>, bool),
+    Learned,
+    LearnedReset,
+    Profile,
+    ProfileSet(String),
+    ProfileClear,
+    Config,
+    Mode,
+    ModeAuto,
+    ModeHold(Option<u64>),
+    ModePause,
     Shutdown,
 }
 
 impl Command {
-    // Parse a command from a string
-    fn from_str(s: &str) -> Result<Self> {
-        match s.to_lowercase().as_str() {
-            "up" => Ok(Command::Up),
-            "down" => Ok(Command::Down),
-            "resample" => Ok(Command::Resample),
-            "shutdown" => Ok(Command::Shutdown),
-            _ => Err(LumctlError::Usage(format!("Unknown command: {}", s))),
+    // Parse a command from the arguments following the program name
+    fn from_args(args: &[String]) -> Result<Self> {
+        let words: Vec<String> = args.iter().map(|a| a.to_lowercase()).collect();
+        let smooth = words.iter().any(|w| w == "--smooth");
+        let flagged = smooth || words.iter().any(|w| w == "--instant");
+        if smooth && words.iter().any(|w| w == "--instant") {
+            return Err(LumctlError::Usage(
+                "Use either --instant or --smooth".into(),
+            ));
         }
+        let instant = !smooth;
+        let words: Vec<&str> = words
+            .iter()
+            .map(String::as_str)
+            .filter(|w| !matches!(*w, "--smooth" | "--instant"))
+            .collect();
+
+        let command = match words.as_slice() {
+            ["up"] => Command::Up(None, instant),
+            ["up", step] => Command::Up(Some(Amount::parse(step)?), instant),
+            ["down"] => Command::Down(None, instant),
+            ["down", step] => Command::Down(Some(Amount::parse(step)?), instant),
+            // Brightness for set is always a percentage, the % is optional
+            ["set", percent] => {
+                let percent = percent.strip_suffix('%').unwrap_or(percent);
+                Command::Set(Amount::parse(&format!("{}%", percent))?, instant)
+            }
+            ["set-raw", raw] => match Amount::parse(raw)? {
+                Amount::Raw(raw) => Command::Set(Amount::Raw(raw), instant),
+                Amount::Percent(_) => {
+                    return Err(LumctlError::Usage(format!(
+                        "set-raw takes raw units, use set for {}",
+                        raw
+                    )));
+                }
+            },
+            ["offset", offset] => match offset.parse() {
+                Ok(offset) => Command::Offset(offset, instant),
+                Err(_) => {
+                    return Err(LumctlError::Usage(format!("Invalid offset: {}", offset)));
+                }
+            },
+            ["resample"] => Command::Resample,
+            ["status"] => Command::Status(false),
+            ["status", "--json"] => Command::Status(true),
+            ["watch", rest @ ..] => Command::Watch(
+                rest.iter()
+                    .filter(|w| **w != "--json")
+                    .map(|w| w.to_string())
+                    .collect(),
+                rest.contains(&"--json"),
+            ),
+            ["learned"] => Command::Learned,
+            ["learned", "reset"] => Command::LearnedReset,
+            ["profile"] => Command::Profile,
+            // Profile names keep their case
+            ["profile", "set", _] => Command::ProfileSet(args[2].clone()),
+            ["profile", "clear"] => Command::ProfileClear,
+            ["config"] => Command::Config,
+            ["mode"] => Command::Mode,
+            ["mode", "auto"] => Command::ModeAuto,
+            ["mode", "hold"] => Command::ModeHold(None),
+            ["mode", "hold", secs] => match secs.parse() {
+                Ok(secs) => Command::ModeHold(Some(secs)),
+                Err(_) => {
+                    return Err(LumctlError::Usage(format!(
+                        "Invalid hold seconds: {}",
+                        secs
+                    )));
+                }
+            },
+            ["mode", "pause"] => Command::ModePause,
+            ["shutdown"] => Command::Shutdown,
+            _ => {
+                return Err(LumctlError::Usage(format!(
+                    "Unknown command: {}",
+                    args.join(" ")
+                )));
+            }
+        };
+
+        let adjusts = matches!(
+            command,
+            Command::Up(..) | Command::Down(..) | Command::Set(..) | Command::Offset(..)
+        );
+        if flagged && !adjusts {
+            return Err(LumctlError::Usage(
+                "--instant and --smooth only apply to up, down, set, set-raw and offset".into(),
+            ));
+        }
+        Ok(command)
     }
 
-    // Convert to a string for sending to the daemon
-    fn as_str(&self) -> &'static str {
+    // Command name and arguments for the daemon
+    fn request(&self) -> (&'static str, Value) {
         match self {
-            Command::Up => "up",
-            Command::Down => "down",
-            Command::Resample => "resample",
-            Command::Shutdown => "shutdown",
+            Command::Up(None, instant) => ("up", json!({ "instant": instant })),
+            Command::Up(Some(step), instant) => ("up", step.args(*instant)),
+            Command::Down(None, instant) => ("down", json!({ "instant": instant })),
+            Command::Down(Some(step), instant) => ("down", step.args(*instant)),
+            Command::Set(value, instant) => ("set", value.args(*instant)),
+            Command::Offset(offset, instant) => {
+                ("offset", json!({ "offset": offset, "instant": instant }))
+            }
+            Command::Resample => ("resample", json!({})),
+            Command::Status(_) => ("status", json!({})),
+            Command::Watch(events, _) if events.is_empty() => ("subscribe", json!({})),
+            Command::Watch(events, _) => ("subscribe", json!({ "events": events })),
+            Command::Learned => ("learned", json!({})),
+            Command::LearnedReset => ("learned-reset", json!({})),
+            Command::Profile => ("config", json!({})),
+            Command::ProfileSet(name) => ("profile", json!({ "name": name })),
+            Command::ProfileClear => ("profile", json!({ "name": null })),
+            Command::Config => ("config", json!({})),
+            Command::Mode => ("status", json!({})),
+            Command::ModeAuto => ("mode", json!({ "mode": "auto" })),
+            Command::ModeHold(secs) => ("mode", json!({ "mode": "hold", "secs": secs })),
+            Command::ModePause => ("mode", json!({ "mode": "pause" })),
+            Command::Shutdown => ("shutdown", json!({})),
         }
     }
 }
@@ -82,22 +241,373 @@ fn get_socket_path() -> Result<PathBuf> {
     Ok(dir.join("lumd.sock"))
 }
 
-fn send_command(command: &Command) -> Result<()> {
-    let socket_path = get_socket_path()?;
+// Print the preferences lumd has learned and the brightness each gives every output
+fn show_learned(learned: &Value) {
+    let path = learned.get("path").and_then(Value::as_str).unwrap_or("?");
+    println!("Learned preferences ({})", path);
+    if learned.get("enabled") == Some(&Value::Bool(false)) {
+        println!("  Not used while learn_preferences is off");
+    }
 
-    // Get the string representation of the command
-    let cmd_str = command.as_str();
+    let samples = learned
+        .get("samples")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    if samples.is_empty() {
+        println!("  (none)");
+    }
+    for sample in samples {
+        let number = |key: &str| sample.get(key).and_then(Value::as_f64).unwrap_or(0.0);
+        let outputs: Vec<String> = sample
+            .get("outputs")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .map(|output| {
+                format!(
+                    "{} {} ({:.0}%)",
+                    output.get("name").and_then(Value::as_str).unwrap_or("?"),
+                    output
+                        .get("brightness")
+                        .and_then(Value::as_i64)
+                        .unwrap_or(0),
+                    output
+                        .get("brightness_percent")
+                        .and_then(Value::as_f64)
+                        .unwrap_or(0.0)
+                )
+            })
+            .collect();
+        println!(
+            "  {:>10.1} lux -> {:>5.1}%  {}",
+            number("lux"),
+            number("percent"),
+            outputs.join(", ")
+        );
+    }
+}
 
-    // Try to connect to the socket
-    match UnixStream::connect(&socket_path) {
-        Ok(mut stream) => {
-            stream.write_all(cmd_str.as_bytes())?;
-            Ok(())
+// Print whether lumd follows the light, holds or is paused
+fn show_mode(status: &Value) {
+    let mode = status.get("mode").and_then(Value::as_str).unwrap_or("auto");
+    match (mode, status.get("hold_until").and_then(Value::as_u64)) {
+        ("manual", Some(until)) => {
+            println!(
+                "Mode: manual hold, {}s left",
+                until.saturating_sub(now_secs())
+            );
         }
-        Err(e) => Err(LumctlError::Connection(format!(
-            "Is lumd running? Error: {}",
-            e
-        ))),
+        ("manual", None) => println!("Mode: manual hold until changed"),
+        (mode, _) => println!("Mode: {}", mode),
+    }
+}
+
+fn show_profiles(reply: &Value) {
+    let active = reply.get("profile").and_then(Value::as_str);
+    println!("Active profile: {}", active.unwrap_or("(base settings)"));
+    if let Some(power) = reply.get("power_profile").and_then(Value::as_str) {
+        println!("Power profile: {}", power);
+    }
+    let profiles: Vec<&str> = reply
+        .get("profiles")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+    if profiles.is_empty() {
+        println!("No profiles configured");
+    }
+    for name in profiles {
+        let marker = if active == Some(name) { '*' } else { ' ' };
+        println!("  {} {}", marker, name);
+    }
+}
+
+fn format_key(key: &str) -> String {
+    if !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        key.to_string()
+    } else {
+        format!("{:?}", key)
+    }
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(format_value).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Object(table) => {
+            let items: Vec<String> = table
+                .iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| format!("{} = {}", format_key(k), format_value(v)))
+                .collect();
+            format!("{{ {} }}", items.join(", "))
+        }
+        // JSON strings, numbers and booleans read the same in TOML
+        other => other.to_string(),
+    }
+}
+
+// Print a table as TOML, plain keys first and then nested tables; unset keys are skipped
+fn print_table(path: &str, table: &serde_json::Map<String, Value>) {
+    let is_table_array = |v: &Value| {
+        v.as_array()
+            .is_some_and(|a| !a.is_empty() && a.iter().all(Value::is_object))
+    };
+
+    for (key, value) in table {
+        if !value.is_null() && !value.is_object() && !is_table_array(value) {
+            println!("{} = {}", format_key(key), format_value(value));
+        }
+    }
+    for (key, value) in table {
+        let name = if path.is_empty() {
+            format_key(key)
+        } else {
+            format!("{}.{}", path, format_key(key))
+        };
+        if let Some(sub) = value.as_object() {
+            println!("\n[{}]", name);
+            print_table(&name, sub);
+        } else if is_table_array(value) {
+            for item in value.as_array().into_iter().flatten() {
+                println!("\n[[{}]]", name);
+                print_table(&name, item.as_object().unwrap_or(&serde_json::Map::new()));
+            }
+        }
+    }
+}
+
+// Print the settings lumd is running with, profiles and defaults included
+fn show_config(reply: &Value) {
+    let text = |key: &str| reply.get(key).and_then(Value::as_str);
+    println!(
+        "# Effective lumd configuration, profile: {}, power profile: {}",
+        text("profile").unwrap_or("(none)"),
+        text("power_profile").unwrap_or("(none)")
+    );
+    if let Some(config) = reply.get("config").and_then(Value::as_object) {
+        print_table("", config);
+    }
+}
+
+// A connection speaking lumd's line-delimited JSON protocol
+struct Connection {
+    writer: UnixStream,
+    reader: BufReader<UnixStream>,
+    next_id: u64,
+}
+
+impl Connection {
+    // Connect and check that lumd speaks our protocol version
+    fn open() -> Result<Self> {
+        let socket_path = get_socket_path()?;
+        let stream = UnixStream::connect(&socket_path)
+            .map_err(|e| LumctlError::Connection(format!("Is lumd running? Error: {}", e)))?;
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        let mut connection = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            next_id: 1,
+        };
+
+        let hello = connection.call("hello", json!({}))?;
+        match hello.get("protocol").and_then(Value::as_u64) {
+            Some(PROTOCOL_VERSION) => Ok(connection),
+            other => Err(LumctlError::Connection(format!(
+                "lumd speaks protocol {:?}, lumctl needs {}",
+                other, PROTOCOL_VERSION
+            ))),
+        }
+    }
+
+    // Send one request and return its result, or the daemon's error
+    fn call(&mut self, cmd: &str, args: Value) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = json!({ "v": PROTOCOL_VERSION, "id": id, "cmd": cmd, "args": args });
+        writeln!(self.writer, "{}", request)?;
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            // Daemons from before the JSON protocol close the connection without a reply
+            return Err(LumctlError::Connection(
+                "lumd closed the connection without replying (is it older than lumctl?)".into(),
+            ));
+        }
+        let response: Value = serde_json::from_str(&line)
+            .map_err(|e| LumctlError::Connection(format!("Invalid reply: {}", e)))?;
+        if response.get("id") != Some(&json!(id)) {
+            return Err(LumctlError::Connection("Reply for another request".into()));
+        }
+
+        match response.get("ok").and_then(Value::as_bool) {
+            Some(true) => Ok(response.get("result").cloned().unwrap_or(Value::Null)),
+            _ => {
+                let error = response.get("error");
+                let field = |name| {
+                    error
+                        .and_then(|e| e.get(name))
+                        .and_then(Value::as_str)
+                        .unwrap_or("unknown")
+                        .to_string()
+                };
+                Err(LumctlError::Daemon(field("code"), field("message")))
+            }
+        }
+    }
+}
+
+fn send_command(command: &Command) -> Result<Value> {
+    let (cmd, args) = command.request();
+    Connection::open()?.call(cmd, args)
+}
+
+// Subscribe and print events until lumd goes away
+fn watch(command: &Command, as_json: bool) -> Result<()> {
+    let (cmd, args) = command.request();
+    let mut connection = Connection::open()?;
+    connection.call(cmd, args)?;
+    // Events may be far apart
+    connection.writer.set_read_timeout(None)?;
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if connection.reader.read_line(&mut line)? == 0 {
+            return Err(LumctlError::Connection("lumd closed the connection".into()));
+        }
+        if as_json {
+            print!("{}", line);
+            continue;
+        }
+
+        let event: Value = serde_json::from_str(&line)
+            .map_err(|e| LumctlError::Connection(format!("Invalid event: {}", e)))?;
+        let fields: Vec<String> = event
+            .get("data")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| match value {
+                Value::String(s) => format!("{}={}", key, s),
+                Value::Number(n) if n.is_f64() => {
+                    format!("{}={:.1}", key, n.as_f64().unwrap_or_default())
+                }
+                other => format!("{}={}", key, other),
+            })
+            .collect();
+        println!(
+            "{:<14} {}",
+            event.get("event").and_then(Value::as_str).unwrap_or("?"),
+            fields.join(" ")
+        );
+    }
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Print a `status` result for humans
+fn print_status(status: &Value) {
+    let text = |key: &str| status.get(key).and_then(Value::as_str).map(String::from);
+    let lux = |key: &str| {
+        status
+            .get(key)
+            .and_then(Value::as_f64)
+            .map_or("-".to_string(), |l| format!("{:.1}", l))
+    };
+
+    let mode = text("mode").unwrap_or_default();
+    match status.get("hold_until").and_then(Value::as_u64) {
+        Some(until) => {
+            println!(
+                "Mode:        {}, {}s left",
+                mode,
+                until.saturating_sub(now_secs())
+            );
+        }
+        None => println!("Mode:        {}", mode),
+    }
+    println!(
+        "Profile:     {}",
+        text("profile").as_deref().unwrap_or("(base settings)")
+    );
+    if let Some(power) = text("power_profile") {
+        println!("Power:       {}", power);
+    }
+    println!(
+        "Offset:      {}",
+        status
+            .get("offset")
+            .and_then(Value::as_i64)
+            .unwrap_or_default()
+    );
+    println!(
+        "Lux:         {} (filtered {})",
+        lux("lux"),
+        lux("filtered_lux")
+    );
+    match status.get("last_sample").and_then(Value::as_u64) {
+        Some(secs) => {
+            println!("Last sample: {}s ago", now_secs().saturating_sub(secs));
+        }
+        None => println!("Last sample: never"),
+    }
+    println!(
+        "Sensor:      {}",
+        text("sensor")
+            .as_deref()
+            .unwrap_or("(none, solar schedule)")
+    );
+
+    for output in status
+        .get("outputs")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        let int = |key: &str| output.get(key).and_then(Value::as_i64).unwrap_or_default();
+        let pct = |key: &str| output.get(key).and_then(Value::as_f64).unwrap_or_default();
+        println!(
+            "Backlight:   {} ({})",
+            output.get("name").and_then(Value::as_str).unwrap_or("?"),
+            output.get("path").and_then(Value::as_str).unwrap_or("?")
+        );
+        println!(
+            "  current {} ({:.0}%), target {} ({:.0}%), max {}",
+            int("current"),
+            pct("current_percent"),
+            int("target"),
+            pct("target_percent"),
+            int("max_brightness")
+        );
+    }
+}
+
+// Manual adjustments put lumd into a manual hold, say so
+fn print_adjusted(reply: &Value) {
+    if reply.get("mode").and_then(Value::as_str) != Some("manual") {
+        return;
+    }
+    match reply.get("hold_until").and_then(Value::as_u64) {
+        Some(until) => println!(
+            "Holding manual brightness, {}s left",
+            until.saturating_sub(now_secs())
+        ),
+        None => println!("Holding manual brightness until changed"),
     }
 }
 
@@ -105,9 +615,26 @@ fn print_usage() {
     eprintln!("lumctl - Control the lumd ambient light daemon");
     eprintln!("Usage: lumctl <command>");
     eprintln!("Commands:");
-    eprintln!("  up        - Increase brightness");
-    eprintln!("  down      - Decrease brightness");
+    eprintln!("  up [step] - Increase brightness, by a raw step or a percentage (5%)");
+    eprintln!("  down [step] - Decrease brightness, by a raw step or a percentage (5%)");
+    eprintln!("  set <percent> - Set brightness to a percentage of the maximum (40%)");
+    eprintln!("  set-raw <value> - Set brightness in raw backlight units");
+    eprintln!("  offset <value> - Set the brightness offset in raw units");
+    eprintln!("  (up, down, set, set-raw and offset take --smooth to fade instead of jumping)");
     eprintln!("  resample  - Force a resampling of ambient light");
+    eprintln!("  status [--json] - Show what lumd is doing, optionally as JSON");
+    eprintln!("  watch [event...] [--json] - Print events as they happen: lux, brightness,");
+    eprintln!("            mode, profile, device-lost, config-reload");
+    eprintln!("  learned   - Show brightness preferences learned from manual adjustments");
+    eprintln!("  learned reset - Forget all learned preferences");
+    eprintln!("  profile   - Show the active and available profiles");
+    eprintln!("  profile set <name> - Switch to a named profile");
+    eprintln!("  profile clear - Go back to the base settings");
+    eprintln!("  config    - Show the settings lumd is running with");
+    eprintln!("  mode      - Show whether lumd follows the light, holds or is paused");
+    eprintln!("  mode auto - Follow the ambient light");
+    eprintln!("  mode hold [secs] - Keep the current brightness, optionally for a while");
+    eprintln!("  mode pause - Stop touching the backlight");
     eprintln!("  shutdown  - Shutdown the daemon");
     eprintln!("Version: {}", env!("CARGO_PKG_VERSION"));
 }
@@ -117,13 +644,13 @@ fn main() {
     let args: Vec<String> = env::args().collect();
 
     // Check that we have a command
-    if args.len() != 2 {
+    if args.len() < 2 {
         print_usage();
         process::exit(1);
     }
 
     // Parse the command
-    let command = match Command::from_str(&args[1]) {
+    let command = match Command::from_args(&args[1..]) {
         Ok(cmd) => cmd,
         Err(e) => {
             eprintln!("Error: {}", e);
@@ -132,8 +659,21 @@ fn main() {
         }
     };
 
-    // Send the command to the daemon
-    match send_command(&command) {
+    // Send the command to the daemon and print its reply
+    let result = match command {
+        Command::Learned => send_command(&command).map(|reply| show_learned(&reply)),
+        Command::Profile => send_command(&command).map(|reply| show_profiles(&reply)),
+        Command::Config => send_command(&command).map(|reply| show_config(&reply)),
+        Command::Mode => send_command(&command).map(|status| show_mode(&status)),
+        Command::Status(true) => send_command(&command).map(|status| println!("{}", status)),
+        Command::Status(false) => send_command(&command).map(|status| print_status(&status)),
+        Command::Watch(_, as_json) => watch(&command, as_json),
+        Command::Up(..) | Command::Down(..) | Command::Set(..) | Command::Offset(..) => {
+            send_command(&command).map(|reply| print_adjusted(&reply))
+        }
+        _ => send_command(&command).map(|_| ()),
+    };
+    match result {
         Ok(_) => (),
         Err(e) => {
             eprintln!("Error: {}", e);
@@ -141,3 +681,67 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Command> {
+        let args: Vec<String> = line.split_whitespace().map(String::from).collect();
+        Command::from_args(&args)
+    }
+
+    fn request(line: &str) -> (&'static str, Value) {
+        parse(line).unwrap().request()
+    }
+
+    #[test]
+    fn brightness_commands_build_their_requests() {
+        assert_eq!(
+            request("set 40%"),
+            ("set", json!({ "percent": 40.0, "instant": true }))
+        );
+        // The % is optional for set
+        assert_eq!(
+            request("set 40"),
+            ("set", json!({ "percent": 40.0, "instant": true }))
+        );
+        assert_eq!(
+            request("set-raw 1200"),
+            ("set", json!({ "raw": 1200, "instant": true }))
+        );
+        assert_eq!(
+            request("up 5%"),
+            ("up", json!({ "percent": 5.0, "instant": true }))
+        );
+        assert_eq!(request("down"), ("down", json!({ "instant": true })));
+        assert_eq!(
+            request("offset -10"),
+            ("offset", json!({ "offset": -10, "instant": true }))
+        );
+    }
+
+    #[test]
+    fn smooth_and_instant_flags() {
+        assert_eq!(
+            request("up 20 --smooth"),
+            ("up", json!({ "raw": 20, "instant": false }))
+        );
+        assert_eq!(
+            request("--instant set 50%"),
+            ("set", json!({ "percent": 50.0, "instant": true }))
+        );
+        assert!(parse("set 50% --smooth --instant").is_err());
+        assert!(parse("status --smooth").is_err());
+        assert!(parse("mode auto --instant").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_amounts() {
+        assert!(parse("set-raw 40%").is_err());
+        assert!(parse("set bright").is_err());
+        assert!(parse("up 5%%").is_err());
+        assert!(parse("offset +-3").is_err());
+        assert!(parse("set").is_err());
+    }
+}
@@ -1,113 +1,565 @@
 use crate::config::Config;
-use crate::device::{lerp, lux_to_brightness, read_brightness, read_lux, set_brightness};
-use crate::error::Result;
-use slog::{Logger, debug, error, info};
-use std::path::Path;
+use crate::curve::MappingMode;
+use crate::device::{find_backlight_devices, read_brightness, read_max_brightness};
+use crate::error::{LumdError, Result};
+use crate::filter::LuxFilter;
+use crate::hysteresis::Hysteresis;
+use crate::learn::LearnedModel;
+use crate::sensor::Sensor;
+use crate::server::{LumdCommand, Request};
+use crate::solar::SolarSchedule;
+use crate::transition::{Fader, LastWritten, TransitionTiming};
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use slog::{Logger, debug, error, info, o, warn};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::fd::AsFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc::Sender};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
+// Selection and limits for one `[[backlight]]` entry
+#[derive(Debug, Clone)]
+pub struct BacklightConfig {
+    // Sysfs device name, e.g. "intel_backlight"; a glob selects every matching device
+    pub device: Option<String>,
+    // Raw brightness limits, defaulting to `min_brightness` and the device maximum
+    pub min_brightness: Option<i32>,
+    pub max_brightness: Option<i32>,
+    // Multiplier applied to the curve percentage for this output
+    pub scale: f32,
+    // Transition overrides, defaulting to the global settings
+    pub transition_steps: Option<u32>,
+    pub step_delay_ms: Option<u64>,
+    pub transition_duration_ms: Option<u64>,
+}
+
+impl Default for BacklightConfig {
+    fn default() -> Self {
+        Self {
+            device: None,
+            min_brightness: None,
+            max_brightness: None,
+            scale: 1.0,
+            transition_steps: None,
+            step_delay_ms: None,
+            transition_duration_ms: None,
+        }
+    }
+}
+
+// How a curve percentage maps onto one output's raw brightness range
+#[derive(Debug, Clone, Copy)]
+pub struct OutputLimits {
+    pub max_brightness: i32,
+    pub min: i32,
+    pub max: i32,
+    pub scale: f32,
+    // Ratio of this output's range to the primary output's, applied to the raw offset
+    pub offset_scale: f32,
+}
+
+impl OutputLimits {
+    // Raw target for a curve percentage, including the scaled offset and limits
+    pub fn target(&self, percent: f32, mode: MappingMode, offset: i32) -> i32 {
+        let percent = (percent * self.scale).clamp(0.0, 100.0);
+        let offset = (offset as f32 * self.offset_scale).round() as i32;
+        let target = mode.percent_to_brightness(percent, self.max_brightness) + offset;
+        target.clamp(self.min.min(self.max), self.max)
+    }
+
+    // Highest raw value `max_percent` allows on this output
+    pub fn cap(&self, mode: MappingMode, max_percent: f32) -> i32 {
+        mode.percent_to_brightness(max_percent, self.max_brightness)
+    }
+
+    // Curve percentage that gives `brightness` with `offset`, the inverse of `target`.
+    // None at a limit or the `max_percent` cap, where many percentages give the same value.
+    pub fn percent(
+        &self,
+        brightness: i32,
+        mode: MappingMode,
+        offset: i32,
+        max_percent: f32,
+    ) -> Option<f32> {
+        let lower = self.min.min(self.max);
+        let upper = self.max.min(self.cap(mode, max_percent));
+        if brightness <= lower || brightness >= upper {
+            return None;
+        }
+        let offset = (offset as f32 * self.offset_scale).round() as i32;
+        let percent = mode.brightness_to_percent(brightness - offset, self.max_brightness);
+        Some((percent / self.scale.max(f32::EPSILON)).clamp(0.0, 100.0))
+    }
+}
+
+// A backlight lumd drives from the shared ambient light reading
+#[derive(Debug)]
+pub struct Output {
+    pub name: String,
+    pub path: PathBuf,
+    pub limits: OutputLimits,
+    pub hysteresis: Hysteresis,
+    // Last raw value lumd wrote, shared with the fader and external change watcher
+    pub last_written: LastWritten,
+    pub fader: Fader,
+    // Brightness set outside lumd, kept as it is by the next adjustment
+    adopted: bool,
+    // Index of the `[[backlight]]` entry that selected this output
+    entry: usize,
+}
+
+// Raw limits for an output, relative to the primary output's range when there is one
+fn output_limits(
+    config: &Config,
+    backlight: &BacklightConfig,
+    max_brightness: i32,
+    primary_max: Option<i32>,
+) -> OutputLimits {
+    let offset_scale = max_brightness as f32 / primary_max.unwrap_or(max_brightness).max(1) as f32;
+    let default_min = if primary_max.is_none() {
+        config.min_brightness
+    } else {
+        ((config.min_brightness as f32 * offset_scale).round() as i32)
+            .max(config.min_brightness.min(1))
+    };
+
+    OutputLimits {
+        max_brightness,
+        min: backlight.min_brightness.unwrap_or(default_min),
+        max: backlight
+            .max_brightness
+            .unwrap_or(max_brightness)
+            .min(max_brightness),
+        scale: backlight.scale,
+        offset_scale,
+    }
+}
+
+// Transition timing for an output; an explicit duration wins over steps * delay at the same level
+fn transition_timing(config: &Config, backlight: &BacklightConfig) -> TransitionTiming {
+    let frame_ms = backlight.step_delay_ms.unwrap_or(config.step_delay_ms);
+    let duration_ms = backlight
+        .transition_duration_ms
+        .or_else(|| backlight.transition_steps.map(|s| s as u64 * frame_ms))
+        .or(config.transition_duration_ms)
+        .unwrap_or(config.transition_steps as u64 * frame_ms);
+    TransitionTiming {
+        duration: Duration::from_millis(duration_ms),
+        frame: Duration::from_millis(frame_ms.max(1)),
+        easing: config.transition_easing,
+        scale_by_distance: config.scale_transition_by_distance,
+        adaptation: config.adaptation.enabled.then_some(config.adaptation),
+    }
+}
+
+// Find and open every configured backlight. The first one is the primary output.
+pub fn open_outputs(log: &Logger, config: &Config) -> Result<Vec<Output>> {
+    let mut outputs: Vec<Output> = Vec::new();
+
+    for (entry, backlight) in config.backlights.iter().enumerate() {
+        for path in find_backlight_devices(log, backlight)? {
+            if outputs.iter().any(|o| o.path == path) {
+                continue;
+            }
+
+            let max_brightness = read_max_brightness(&path)?;
+            let primary_max = outputs.first().map(|o| o.limits.max_brightness);
+            let limits = output_limits(config, backlight, max_brightness, primary_max);
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let transition = transition_timing(config, backlight);
+
+            info!(log, "Managing backlight output";
+                "device" => &name,
+                "max_brightness" => max_brightness,
+                "min" => limits.min,
+                "max" => limits.max,
+                "scale" => limits.scale,
+                "transition_ms" => transition.duration.as_millis());
+
+            let last_written = LastWritten::new(read_brightness(&path)?);
+            let fader = Fader::spawn(
+                log.new(o!("output" => name.clone())),
+                path.clone(),
+                last_written.clone(),
+                transition,
+                config.mapping_mode,
+                max_brightness,
+            );
+            outputs.push(Output {
+                name,
+                path,
+                limits,
+                hysteresis: Hysteresis::new(config.hysteresis.clone()),
+                last_written,
+                fader,
+                adopted: false,
+                entry,
+            });
+        }
+    }
+
+    if outputs.is_empty() {
+        return Err(LumdError::DeviceNotFound(
+            "No backlight device found".into(),
+        ));
+    }
+    Ok(outputs)
+}
+
+// Apply limits, thresholds and transition settings from a new `config` to outputs
+// opened with the same backlight entries
+pub fn reconfigure_outputs(log: &Logger, outputs: &mut [Output], config: &Config) {
+    let primary_max = outputs[0].limits.max_brightness;
+    for (index, output) in outputs.iter_mut().enumerate() {
+        let backlight = &config.backlights[output.entry];
+        let primary_max = (index > 0).then_some(primary_max);
+        output.limits = output_limits(config, backlight, output.limits.max_brightness, primary_max);
+        output.hysteresis = Hysteresis::new(config.hysteresis.clone());
+        let transition = transition_timing(config, backlight);
+        output.fader.configure(transition, config.mapping_mode);
+        debug!(log, "Reconfigured backlight output";
+            "device" => &output.name,
+            "min" => output.limits.min,
+            "max" => output.limits.max,
+            "transition_ms" => transition.duration.as_millis());
+    }
+}
+
+// Outcome of a single ambient light adjustment
+#[derive(Debug, Clone, Copy)]
+pub struct Adjustment {
+    pub changed: bool,
+    // Sensor reading before and after smoothing
+    pub raw_lux: Option<f32>,
+    pub lux: Option<f32>,
+    // Target of the primary output
+    pub target: i32,
+    // When the light was measured, None when the sensor had no new sample
+    pub time: Option<SystemTime>,
+}
+
+// Curve percentage lumd aims for at `lux`, including the learned correction
+pub fn curve_percent(lux: f32, config: &Config, learned: &LearnedModel) -> f32 {
+    if config.learn_preferences {
+        learned.percent_at(&config.curve, lux, config.mapping_mode)
+    } else {
+        config.curve.percent_at(lux, config.mapping_mode)
+    }
+}
+
+// Sample the light and hand new targets to each output's fader without waiting
+// for the fades to finish
 #[allow(clippy::too_many_arguments)]
 pub fn read_and_adjust_ambient_light(
     log: &Logger,
-    iio_path: &Path,
-    backlight_path: &Path,
-    max_brightness: i32,
+    sensor: Option<&mut Sensor>,
+    schedule: Option<&SolarSchedule>,
+    outputs: &mut [Output],
     config: &Config,
+    learned: &LearnedModel,
+    filter: &mut LuxFilter,
     offset: i32,
     instant: bool,
     force: bool,
-) -> Result<bool> {
+) -> Result<Adjustment> {
     info!(log, "Reading ambient light and adjusting brightness");
 
-    let mut current_brightness = read_brightness(backlight_path)?;
-    let mut target_brightness = current_brightness;
-    let should_force = force; // Rename to avoid the unused assignment
-    let threshold = config.brightness_threshold;
-
-    match read_lux(iio_path) {
-        Ok(lux) => {
-            let mut new_target = lux_to_brightness(lux, max_brightness);
-            new_target += offset;
-            if new_target < config.min_brightness {
-                new_target = config.min_brightness;
-            } else if new_target > max_brightness {
-                new_target = max_brightness
+    // Judge a running fade by where it is heading, not where it happens to be
+    let current: Vec<i32> = outputs
+        .iter()
+        .map(|o| {
+            if o.fader.is_moving() {
+                Ok(o.fader.target())
+            } else {
+                read_brightness(&o.path)
             }
+        })
+        .collect::<Result<_>>()?;
 
-            if new_target != target_brightness
-                && (((new_target - target_brightness).abs() > threshold || instant) || should_force)
-            {
-                target_brightness = new_target;
-            }
+    // The schedule moves with the sun, so it is evaluated on every sample
+    let scheduled = schedule.map(|schedule| {
+        let elevation = schedule.elevation_now();
+        let percent = schedule.percent_at(elevation);
+        debug!(log, "Solar schedule";
+            "sun_elevation" => format!("{:.1}", elevation),
+            "percent" => format!("{:.1}", percent));
+        percent
+    });
 
-            debug!(log, "Light and brightness data";
-                "lux" => format!("{:.1}", lux),
-                "current_brightness" => current_brightness,
-                "min" => config.min_brightness,
-                "max" => max_brightness,
-                "offset" => offset,
-                "target" => target_brightness,
-                "instant" => instant,
-                "force" => should_force
-            );
+    let (raw_lux, lux, percent, time) = match (sensor, scheduled) {
+        (Some(sensor), _) => {
+            let sample = match sensor.read_lux() {
+                Ok(sample) => sample,
+                Err(e) => {
+                    error!(log, "Failed to read lux: {}", e);
+                    return Ok(Adjustment {
+                        changed: false,
+                        raw_lux: None,
+                        lux: None,
+                        target: current[0],
+                        time: Some(SystemTime::now()),
+                    });
+                }
+            };
+            let (raw_lux, lux, time) = match (sample, filter.last()) {
+                (Some(sample), _) => (
+                    Some(sample.lux),
+                    filter.apply(sample.lux),
+                    Some(sample.time),
+                ),
+                // Nothing new from the buffer, but a change the user asked for still
+                // applies at the light level seen last
+                (None, Some(last)) if instant || force => (None, last, None),
+                (None, _) => {
+                    debug!(log, "No new light sample");
+                    return Ok(Adjustment {
+                        changed: false,
+                        raw_lux: None,
+                        lux: None,
+                        target: current[0],
+                        time: None,
+                    });
+                }
+            };
+            let mut percent = curve_percent(lux, config, learned);
+            if let (Some(schedule), Some(scheduled)) = (schedule, scheduled) {
+                percent = schedule.apply(percent, scheduled);
+            }
+            (raw_lux, Some(lux), percent, time)
         }
-        Err(e) => {
-            error!(log, "Failed to read lux: {}", e);
-            return Ok(false);
+        (None, Some(scheduled)) => (None, None, scheduled, Some(SystemTime::now())),
+        (None, None) => {
+            return Err(LumdError::DeviceNotFound(
+                "No light sensor or solar schedule".into(),
+            ));
         }
+    };
+
+    let mut targets = Vec::with_capacity(outputs.len());
+    for (output, &current_brightness) in outputs.iter_mut().zip(&current) {
+        let cap = output.limits.cap(config.mapping_mode, config.max_percent);
+        let new_target = output
+            .limits
+            .target(percent, config.mapping_mode, offset)
+            .min(cap);
+
+        // Without a sensor the schedule drifts slowly enough to follow directly
+        let adjust = match lux {
+            Some(lux) if instant || force => {
+                output.hysteresis.accept(lux);
+                true
+            }
+            Some(lux) => output
+                .hysteresis
+                .should_adjust(lux, current_brightness, new_target),
+            None => true,
+        };
+        // An adopted external value may lie outside the limits, leave it alone
+        let target_brightness = if std::mem::take(&mut output.adopted) || !adjust {
+            current_brightness
+        } else {
+            new_target
+        };
+
+        debug!(log, "Light and brightness data";
+            "output" => &output.name,
+            "raw_lux" => raw_lux.map(|l| format!("{:.1}", l)),
+            "lux" => lux.map(|l| format!("{:.1}", l)),
+            "percent" => format!("{:.1}", percent),
+            "current_brightness" => current_brightness,
+            "min" => output.limits.min,
+            "max" => output.limits.max,
+            "offset" => offset,
+            "target" => target_brightness,
+            "instant" => instant,
+            "force" => force,
+            "spike_held" => filter.is_holding(),
+            "dwell_pending" => output.hysteresis.is_pending()
+        );
+        targets.push(target_brightness);
     }
 
-    if !should_force && (current_brightness == target_brightness) {
-        return Ok(false);
+    if !force && current == targets {
+        return Ok(Adjustment {
+            changed: false,
+            raw_lux,
+            lux,
+            target: targets[0],
+            time,
+        });
     }
 
-    if instant {
-        set_brightness(backlight_path, target_brightness)?;
-        return Ok(true);
+    for (output, &target) in outputs.iter().zip(&targets) {
+        output.fader.set_target(target, instant);
     }
 
-    // Gradual brightness adjustment with steps
-    let steps = config.transition_steps;
-    let delay = Duration::from_millis(config.step_delay_ms);
-    let start_brightness = current_brightness;
+    Ok(Adjustment {
+        changed: true,
+        raw_lux,
+        lux,
+        target: targets[0],
+        time,
+    })
+}
 
-    for i in 0..steps {
-        let t = (i as f32) / (steps as f32);
-        let interp = lerp(start_brightness as f32, target_brightness as f32, t).round() as i32;
+// Offset change, in primary output units, implied by a brightness lumd did not write.
+// The first output found changed is adopted as the new baseline.
+pub fn external_change(log: &Logger, outputs: &mut [Output]) -> Result<Option<i32>> {
+    for output in outputs {
+        // A running or queued fade watches for this itself
+        if output.fader.is_moving() {
+            continue;
+        }
+        let Some((written, current)) = output.last_written.adopt_external(&output.path)? else {
+            continue;
+        };
 
-        debug!(log, "Brightness adjustment step";
-            "step" => i,
-            "current" => current_brightness,
-            "interpolated" => interp
-        );
+        info!(log, "Detected external brightness change";
+            "output" => &output.name,
+            "written" => written,
+            "current" => current);
+        output.fader.adopt(current);
+        output.adopted = true;
+        let delta = (current - written) as f32 / output.limits.offset_scale.max(f32::EPSILON);
+        return Ok(Some(delta.round() as i32));
+    }
+    Ok(None)
+}
+
+// Forget what lumd last wrote and carry on from the brightness the outputs hold now,
+// so the next target is written even if it matches a value from before
+pub fn resync_outputs(log: &Logger, outputs: &[Output]) {
+    for output in outputs {
+        match output.last_written.resync(&output.path) {
+            Ok(current) => {
+                debug!(log, "Resynced brightness";
+                    "output" => &output.name,
+                    "current" => current);
+                output.fader.adopt(current);
+            }
+            Err(e) => warn!(log, "Failed to read brightness";
+                "output" => &output.name,
+                "error" => %e),
+        }
+    }
+}
+
+// Start a thread that sends `LumdCommand::BrightnessChanged` when a backlight
+// reports a brightness lumd did not write.
+// The backlight class notifies pollers of `actual_brightness` on every change, so
+// hardware keys and tools like brightnessctl wake the sampler straight away.
+// `brightness` is never notified, but it is what gets compared with lumd's writes:
+// `actual_brightness` is whatever the hardware settled on and can differ from the
+// value written even when nobody else touched the backlight.
+pub fn spawn_brightness_watcher(
+    log: Logger,
+    outputs: &[Output],
+    tx: Sender<Request>,
+    running: Arc<AtomicBool>,
+) -> Result<()> {
+    let mut watched = Vec::with_capacity(outputs.len());
+    for output in outputs {
+        let file = File::open(output.path.join("actual_brightness"))?;
+        watched.push((file, output.path.clone(), output.last_written.clone()));
+    }
+
+    thread::spawn(move || {
+        info!(log, "Watching for external brightness changes");
+        let mut buf = String::new();
+        while running.load(Ordering::SeqCst) {
+            // sysfs only signals readers that consumed the current value
+            for (file, _, _) in watched.iter_mut() {
+                buf.clear();
+                if file
+                    .seek(SeekFrom::Start(0))
+                    .and_then(|_| file.read_to_string(&mut buf))
+                    .is_err()
+                {
+                    warn!(log, "Failed to read actual_brightness");
+                }
+            }
 
-        set_brightness(backlight_path, interp)?;
-        current_brightness = interp;
-
-        // Check if ambient light has changed significantly during transition
-        if let Ok(lux) = read_lux(iio_path) {
-            let mut new_target = lux_to_brightness(lux, max_brightness);
-            new_target += offset;
-            if new_target < config.min_brightness {
-                new_target = config.min_brightness;
-            } else if new_target > max_brightness {
-                new_target = max_brightness
+            let mut fds: Vec<PollFd> = watched
+                .iter()
+                .map(|(file, _, _)| PollFd::new(file.as_fd(), PollFlags::POLLPRI))
+                .collect();
+            match poll(&mut fds, PollTimeout::from(1000u16)) {
+                Ok(0) => continue,
+                Ok(_) => {}
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(e) => {
+                    error!(log, "Polling actual_brightness failed"; "error" => %e);
+                    break;
+                }
             }
 
-            if new_target != target_brightness && (new_target - target_brightness).abs() > threshold
-            {
-                target_brightness = new_target;
-                break; // restart transition with new target
+            let external = watched
+                .iter()
+                .any(|(_, path, last_written)| last_written.differs(path).unwrap_or(false));
+            if external {
+                debug!(log, "Backlight changed outside lumd");
+                if tx.send(LumdCommand::BrightnessChanged.into()).is_err() {
+                    break;
+                }
             }
         }
+    });
 
-        thread::sleep(delay)
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_brightness: i32, min: i32, max: i32, scale: f32) -> OutputLimits {
+        OutputLimits {
+            max_brightness,
+            min,
+            max,
+            scale,
+            offset_scale: max_brightness as f32 / 1000.0,
+        }
     }
 
-    // Make sure we reach the final target
-    if current_brightness != target_brightness {
-        set_brightness(backlight_path, target_brightness)?;
+    #[test]
+    fn target_applies_scale_offset_and_limits() {
+        let primary = limits(1000, 10, 1000, 1.0);
+        assert_eq!(primary.target(50.0, MappingMode::Linear, 0), 500);
+        assert_eq!(primary.target(50.0, MappingMode::Linear, 40), 540);
+        assert_eq!(primary.target(0.0, MappingMode::Linear, 0), 10);
+        assert_eq!(primary.target(100.0, MappingMode::Linear, 50), 1000);
+
+        // A second output with a quarter of the range gets a quarter of the offset
+        let secondary = limits(250, 1, 200, 0.5);
+        assert_eq!(secondary.target(80.0, MappingMode::Linear, 40), 110);
+        assert_eq!(secondary.target(100.0, MappingMode::Linear, 400), 200);
     }
 
-    Ok(true)
+    #[test]
+    fn percent_inverts_target() {
+        let halved = limits(1000, 10, 1000, 0.5);
+        for mode in [MappingMode::Linear, MappingMode::Cie] {
+            let target = halved.target(60.0, mode, 40);
+            let percent = halved.percent(target, mode, 40, 100.0).unwrap();
+            assert!((percent - 60.0).abs() < 0.5, "{:?} {}", mode, percent);
+        }
+
+        // At a limit or the cap any higher or lower percentage looks the same
+        let secondary = limits(250, 1, 200, 0.5);
+        assert_eq!(secondary.percent(200, MappingMode::Linear, 0, 100.0), None);
+        assert_eq!(secondary.percent(1, MappingMode::Linear, 0, 100.0), None);
+        assert_eq!(secondary.percent(125, MappingMode::Linear, 0, 50.0), None);
+        assert!(
+            secondary
+                .percent(124, MappingMode::Linear, 0, 50.0)
+                .is_some()
+        );
+    }
 }
@@ -0,0 +1,154 @@
+use crate::error::{LumdError, Result};
+use crate::server::PROTOCOL_VERSION;
+use serde_json::{Value, json};
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicU64, Ordering},
+    mpsc::{self, Receiver, Sender},
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Kinds of events clients can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    // Every sensor reading, raw and smoothed
+    Lux,
+    // New brightness targets, from the light or the user
+    Brightness,
+    Mode,
+    // Named profile or power profile switched
+    Profile,
+    // A sensor or backlight stopped responding
+    DeviceLost,
+    // Settings rebuilt after a profile or power source change
+    ConfigReload,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 6] = [
+        EventKind::Lux,
+        EventKind::Brightness,
+        EventKind::Mode,
+        EventKind::Profile,
+        EventKind::DeviceLost,
+        EventKind::ConfigReload,
+    ];
+
+    pub fn from_str(s: &str) -> Result<Self> {
+        EventKind::ALL
+            .into_iter()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| {
+                LumdError::InvalidData(format!(
+                    "Unknown event {:?}, expected one of lux, brightness, mode, profile, device-lost, config-reload",
+                    s
+                ))
+            })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Lux => "lux",
+            EventKind::Brightness => "brightness",
+            EventKind::Mode => "mode",
+            EventKind::Profile => "profile",
+            EventKind::DeviceLost => "device-lost",
+            EventKind::ConfigReload => "config-reload",
+        }
+    }
+}
+
+struct Subscriber {
+    id: u64,
+    kinds: Vec<EventKind>,
+    tx: Sender<Value>,
+}
+
+// Events for one subscriber, ending once it is unsubscribed
+pub struct Subscription {
+    pub id: u64,
+    pub events: Receiver<Value>,
+}
+
+// Hands events from the sampler loop to every subscribed client
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl EventBus {
+    // Receive every event of the given kinds from now on
+    pub fn subscribe(&self, kinds: Vec<EventKind>) -> Subscription {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(Subscriber { id, kinds, tx });
+        }
+        Subscription { id, events: rx }
+    }
+
+    // Stop sending events to a subscriber, e.g. after its client went away
+    pub fn unsubscribe(&self, id: u64) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.retain(|s| s.id != id);
+        }
+    }
+
+    // Send an event to its subscribers, dropping those that went away
+    pub fn publish(&self, kind: EventKind, data: Value) {
+        let Ok(mut subscribers) = self.subscribers.lock() else {
+            return;
+        };
+        if !subscribers.iter().any(|s| s.kinds.contains(&kind)) {
+            return;
+        }
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        let event = json!({
+            "v": PROTOCOL_VERSION,
+            "event": kind.name(),
+            "time": time,
+            "data": data,
+        });
+        subscribers.retain(|s| !s.kinds.contains(&kind) || s.tx.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publish_reaches_matching_subscribers() {
+        let bus = EventBus::default();
+        let lux = bus.subscribe(vec![EventKind::Lux]);
+        let mode = bus.subscribe(vec![EventKind::Mode]);
+
+        bus.publish(EventKind::Lux, json!({ "lux": 12.0 }));
+        let event = lux.events.try_recv().unwrap();
+        assert_eq!(event["event"], "lux");
+        assert_eq!(event["data"]["lux"], 12.0);
+        assert!(mode.events.try_recv().is_err());
+    }
+
+    #[test]
+    fn unsubscribe_ends_the_stream() {
+        let bus = EventBus::default();
+        let first = bus.subscribe(EventKind::ALL.to_vec());
+        let second = bus.subscribe(EventKind::ALL.to_vec());
+        assert_ne!(first.id, second.id);
+
+        bus.unsubscribe(first.id);
+        assert_eq!(
+            first.events.try_recv(),
+            Err(mpsc::TryRecvError::Disconnected)
+        );
+        bus.publish(EventKind::Mode, json!({}));
+        assert!(second.events.try_recv().is_ok());
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+    }
+}
@@ -1,24 +1,90 @@
+use crate::backlight::BacklightConfig;
+use crate::curve::{Curve, CurvePoint, MappingMode};
 use crate::error::{LumdError, Result};
+use crate::events::SensingMode;
+use crate::filter::{FilterConfig, FilterKind};
+use crate::hysteresis::{HysteresisConfig, Threshold};
+use crate::keyboard::KeyboardConfig;
+use crate::power::PowerConfig;
+use crate::profile::ProfileOverrides;
+use crate::sensor::{SensorBackend, SensorConfig};
+use crate::solar::{ScheduleBlend, ScheduleConfig};
+use crate::transition::{AdaptationConfig, Easing};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
 
+// Settings that pick devices or start threads, read once at startup
+const STARTUP_KEYS: [&str; 7] = [
+    "sensor",
+    "sensor_backend",
+    "buffer_sampling_hz",
+    "backlight",
+    "sensing_mode",
+    "event_window_percent",
+    "follow_external_changes",
+];
+
 // Simple configuration struct without serde derive macros
 #[derive(Debug, Clone)]
 pub struct Config {
     // Backlight settings
     pub min_brightness: i32,
     pub brightness_offset: i32,
+    // Hard cap in percent of each output's maximum
+    pub max_percent: f32,
+    // Put back the brightness found at startup when lumd exits cleanly
+    pub restore_brightness_on_exit: bool,
 
     // Sampling settings
     pub sample_interval_secs: u64,
     pub transition_steps: u32,
     pub step_delay_ms: u64,
+    // Transition length, defaulting to transition_steps * step_delay_ms
+    pub transition_duration_ms: Option<u64>,
+    pub transition_easing: Easing,
+    pub scale_transition_by_distance: bool,
+    pub adaptation: AdaptationConfig,
+
+    // Sensor access
+    pub sensor_backend: SensorBackend,
+    pub buffer_sampling_hz: Option<f32>,
+    pub sensor: SensorConfig,
+    pub backlights: Vec<BacklightConfig>,
+    pub keyboard: KeyboardConfig,
+    pub schedule: ScheduleConfig,
+    pub power: PowerConfig,
 
-    // Interpolation threshold
-    pub brightness_threshold: i32,
+    // Event driven sensing
+    pub sensing_mode: SensingMode,
+    pub event_window_percent: f32,
+    pub event_max_sleep_secs: u64,
+
+    // Thresholds and dwell time before reacting to a light change
+    pub hysteresis: HysteresisConfig,
 
     // Adjustment amount for manual controls
     pub manual_adjustment_amount: i32,
+    // How long a manual adjustment holds before lumd follows the light again, 0 for not at all
+    pub manual_hold_secs: u64,
+
+    // Lux to brightness mapping
+    pub curve: Curve,
+    pub mapping_mode: MappingMode,
+
+    // Learn a personalised curve from manual adjustments
+    pub learn_preferences: bool,
+    // Adopt brightness changes made outside lumd instead of overwriting them
+    pub follow_external_changes: bool,
+
+    // Sensor smoothing
+    pub filter: FilterConfig,
+
+    // Named profiles from `[profiles.<name>]`, each overriding any subset of settings
+    pub profiles: BTreeMap<String, toml::Table>,
+    // Settings as written in the file, the base profiles are merged onto
+    raw: toml::Table,
 }
 
 impl Default for Config {
@@ -26,11 +92,35 @@ impl Default for Config {
         Self {
             min_brightness: 1,
             brightness_offset: 40,
+            max_percent: 100.0,
+            restore_brightness_on_exit: false,
             sample_interval_secs: 3,
             transition_steps: 10,
             step_delay_ms: 10,
-            brightness_threshold: 8,
+            transition_duration_ms: None,
+            transition_easing: Easing::default(),
+            scale_transition_by_distance: false,
+            adaptation: AdaptationConfig::default(),
+            sensor_backend: SensorBackend::default(),
+            buffer_sampling_hz: None,
+            sensor: SensorConfig::default(),
+            backlights: vec![BacklightConfig::default()],
+            keyboard: KeyboardConfig::default(),
+            schedule: ScheduleConfig::default(),
+            power: PowerConfig::default(),
+            sensing_mode: SensingMode::default(),
+            event_window_percent: 10.0,
+            event_max_sleep_secs: 300,
+            hysteresis: HysteresisConfig::from_brightness_threshold(8),
             manual_adjustment_amount: 8,
+            manual_hold_secs: 30,
+            curve: Curve::default(),
+            mapping_mode: MappingMode::default(),
+            learn_preferences: false,
+            follow_external_changes: true,
+            filter: FilterConfig::default(),
+            profiles: BTreeMap::new(),
+            raw: toml::Table::new(),
         }
     }
 }
@@ -47,12 +137,156 @@ impl Config {
 
         let content = fs::read_to_string(path).map_err(LumdError::from)?;
 
-        // Create config with default values that will be overridden by any values in the file
-        let mut config = Self::default();
-
         // Parse the TOML content using toml crate directly
         let parsed: toml::Value = toml::from_str(&content)
             .map_err(|e| LumdError::InvalidData(format!("Config parse error: {}", e)))?;
+        let config = Self::from_value(&parsed)?;
+
+        // Catch mistakes in profiles now rather than when switching to them
+        for name in config.profiles.keys() {
+            config.with_profile(Some(name)).map_err(|e| {
+                LumdError::InvalidData(format!("Invalid profile {:?}: {}", name, e))
+            })?;
+        }
+
+        Ok(config)
+    }
+
+    // This configuration with the named profile merged over the file's settings
+    pub fn with_profile(&self, name: Option<&str>) -> Result<Self> {
+        let Some(name) = name else {
+            return Self::from_value(&toml::Value::Table(self.raw_with_profiles()));
+        };
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| LumdError::InvalidData(format!("Unknown profile: {}", name)))?;
+        if let Some(key) = profile.keys().find(|k| STARTUP_KEYS.contains(&k.as_str())) {
+            return Err(LumdError::InvalidData(format!(
+                "Profile {} sets {}, which is only read at startup",
+                name, key
+            )));
+        }
+
+        let mut merged = self.raw.clone();
+        merge_tables(&mut merged, profile);
+        let mut config = Self::from_value(&toml::Value::Table(merged))?;
+        config.profiles = self.profiles.clone();
+        config.raw = self.raw.clone();
+        Ok(config)
+    }
+
+    // The file's settings including its profiles
+    fn raw_with_profiles(&self) -> toml::Table {
+        let mut table = self.raw.clone();
+        let profiles = self
+            .profiles
+            .iter()
+            .map(|(name, profile)| (name.clone(), toml::Value::Table(profile.clone())))
+            .collect();
+        table.insert("profiles".into(), toml::Value::Table(profiles));
+        table
+    }
+
+    // The settings in effect, defaults included, as reported by the `config` request
+    pub fn to_json(&self) -> Value {
+        let backlights: Vec<Value> = self
+            .backlights
+            .iter()
+            .map(|b| {
+                json!({
+                    "device": b.device,
+                    "min_brightness": b.min_brightness,
+                    "max_brightness": b.max_brightness,
+                    "scale": b.scale,
+                    "transition_steps": b.transition_steps,
+                    "step_delay_ms": b.step_delay_ms,
+                    "transition_duration_ms": b.transition_duration_ms,
+                })
+            })
+            .collect();
+        let points: Vec<Value> = self
+            .curve
+            .points()
+            .iter()
+            .map(|p| json!([p.lux, p.percent]))
+            .collect();
+
+        json!({
+            "min_brightness": self.min_brightness,
+            "brightness_offset": self.brightness_offset,
+            "max_percent": self.max_percent,
+            "restore_brightness_on_exit": self.restore_brightness_on_exit,
+            "sample_interval_secs": self.sample_interval_secs,
+            "transition_steps": self.transition_steps,
+            "step_delay_ms": self.step_delay_ms,
+            "transition_duration_ms": self.transition_duration_ms,
+            "transition_easing": self.transition_easing.name(),
+            "scale_transition_by_distance": self.scale_transition_by_distance,
+            "adaptation": {
+                "enabled": self.adaptation.enabled,
+                "rising_secs": self.adaptation.rising_secs,
+                "falling_secs": self.adaptation.falling_secs,
+            },
+            "sensor_backend": self.sensor_backend.name(),
+            "buffer_sampling_hz": self.buffer_sampling_hz,
+            "sensor": {
+                "device": self.sensor.device,
+                "name": self.sensor.name,
+                "clear_coefficient": self.sensor.clear_coefficient,
+                "both_coefficient": self.sensor.both_coefficient,
+                "trigger": self.sensor.trigger,
+            },
+            "backlight": backlights,
+            "keyboard": {
+                "enabled": self.keyboard.enabled,
+                "device": self.keyboard.device,
+                "on_below_lux": self.keyboard.on_below_lux,
+                "off_above_lux": self.keyboard.off_above_lux,
+                "brightness": self.keyboard.brightness,
+            },
+            "schedule": {
+                "latitude": self.schedule.latitude,
+                "longitude": self.schedule.longitude,
+                "day_percent": self.schedule.day_percent,
+                "night_percent": self.schedule.night_percent,
+                "full_day_elevation": self.schedule.full_day_elevation,
+                "blend": self.schedule.blend.name(),
+                "bias_weight": self.schedule.bias_weight,
+            },
+            "power": {
+                "ac": self.power.ac.to_json(),
+                "battery": self.power.battery.to_json(),
+                "low_battery_percent": self.power.low_battery_percent,
+                "low_battery_max_percent": self.power.low_battery_max_percent,
+            },
+            "sensing_mode": self.sensing_mode.name(),
+            "event_window_percent": self.event_window_percent,
+            "event_max_sleep_secs": self.event_max_sleep_secs,
+            "hysteresis": {
+                "rising": self.hysteresis.rising.describe(),
+                "falling": self.hysteresis.falling.describe(),
+                "dwell_ms": self.hysteresis.dwell_ms,
+            },
+            "manual_adjustment_amount": self.manual_adjustment_amount,
+            "manual_hold_secs": self.manual_hold_secs,
+            "curve": { "points": points },
+            "mapping_mode": self.mapping_mode.name(),
+            "learn_preferences": self.learn_preferences,
+            "follow_external_changes": self.follow_external_changes,
+            "filter": {
+                "kind": self.filter.kind.name(),
+                "window": self.filter.window,
+                "alpha": self.filter.alpha,
+                "spike_threshold": self.filter.spike_threshold,
+                "spike_duration_ms": self.filter.spike_duration_ms,
+            },
+        })
+    }
+
+    fn from_value(parsed: &toml::Value) -> Result<Self> {
+        // Create config with default values that will be overridden by any values in the file
+        let mut config = Self::default();
 
         // Extract values from the parsed TOML if they exist
         if let Some(table) = parsed.as_table() {
@@ -65,6 +299,10 @@ impl Config {
                 config.brightness_offset = value as i32;
             }
 
+            if let Some(value) = table.get("max_percent").and_then(as_number) {
+                config.max_percent = value.clamp(0.0, 100.0);
+            }
+
             // Sampling settings
             if let Some(value) = table
                 .get("sample_interval_secs")
@@ -81,12 +319,104 @@ impl Config {
                 config.step_delay_ms = value as u64;
             }
 
+            if let Some(value) = table
+                .get("transition_duration_ms")
+                .and_then(|v| v.as_integer())
+            {
+                config.transition_duration_ms = Some(value as u64);
+            }
+
+            if let Some(value) = table.get("transition_easing").and_then(|v| v.as_str()) {
+                config.transition_easing = Easing::from_str(value)?;
+            }
+
+            if let Some(value) = table
+                .get("scale_transition_by_distance")
+                .and_then(|v| v.as_bool())
+            {
+                config.scale_transition_by_distance = value;
+            }
+
+            if let Some(adaptation) = table.get("adaptation").and_then(|v| v.as_table()) {
+                config.adaptation = parse_adaptation(adaptation)?;
+            }
+
+            // Sensor access
+            if let Some(value) = table.get("sensor_backend").and_then(|v| v.as_str()) {
+                config.sensor_backend = SensorBackend::from_str(value)?;
+            }
+
+            if let Some(value) = table.get("buffer_sampling_hz").and_then(as_number) {
+                config.buffer_sampling_hz = Some(value);
+            }
+
+            if let Some(sensor) = table.get("sensor").and_then(|v| v.as_table()) {
+                config.sensor = parse_sensor(sensor)?;
+            }
+
+            // Either a single [backlight] table or several [[backlight]] entries
+            match table.get("backlight") {
+                Some(toml::Value::Table(backlight)) => {
+                    config.backlights = vec![parse_backlight(backlight)?];
+                }
+                Some(toml::Value::Array(entries)) => {
+                    config.backlights = entries
+                        .iter()
+                        .map(|entry| {
+                            entry.as_table().map(parse_backlight).unwrap_or_else(|| {
+                                Err(LumdError::InvalidData(
+                                    "Backlight entries must be tables".into(),
+                                ))
+                            })
+                        })
+                        .collect::<Result<_>>()?;
+                }
+                Some(_) => {
+                    return Err(LumdError::InvalidData(
+                        "backlight must be a table or an array of tables".into(),
+                    ));
+                }
+                None => {}
+            }
+
+            if let Some(keyboard) = table.get("keyboard").and_then(|v| v.as_table()) {
+                config.keyboard = parse_keyboard(keyboard);
+            }
+
+            if let Some(power) = table.get("power").and_then(|v| v.as_table()) {
+                config.power = parse_power(power)?;
+            }
+
+            if let Some(schedule) = table.get("schedule").and_then(|v| v.as_table()) {
+                config.schedule = parse_schedule(schedule)?;
+            }
+
+            // Event driven sensing
+            if let Some(value) = table.get("sensing_mode").and_then(|v| v.as_str()) {
+                config.sensing_mode = SensingMode::from_str(value)?;
+            }
+
+            if let Some(value) = table.get("event_window_percent").and_then(as_number) {
+                config.event_window_percent = value;
+            }
+
+            if let Some(value) = table
+                .get("event_max_sleep_secs")
+                .and_then(|v| v.as_integer())
+            {
+                config.event_max_sleep_secs = value as u64;
+            }
+
             // Interpolation threshold
             if let Some(value) = table
                 .get("brightness_threshold")
                 .and_then(|v| v.as_integer())
             {
-                config.brightness_threshold = value as i32;
+                config.hysteresis = HysteresisConfig::from_brightness_threshold(value as i32);
+            }
+
+            if let Some(hysteresis) = table.get("hysteresis").and_then(|v| v.as_table()) {
+                config.hysteresis = parse_hysteresis(hysteresis, &config.hysteresis)?;
             }
 
             // Adjustment amount
@@ -96,8 +426,463 @@ impl Config {
             {
                 config.manual_adjustment_amount = value as i32;
             }
+
+            if let Some(value) = table.get("manual_hold_secs").and_then(|v| v.as_integer()) {
+                config.manual_hold_secs = value.max(0) as u64;
+            }
+
+            // Learning
+            if let Some(value) = table.get("learn_preferences").and_then(|v| v.as_bool()) {
+                config.learn_preferences = value;
+            }
+
+            if let Some(value) = table
+                .get("restore_brightness_on_exit")
+                .and_then(|v| v.as_bool())
+            {
+                config.restore_brightness_on_exit = value;
+            }
+
+            if let Some(value) = table
+                .get("follow_external_changes")
+                .and_then(|v| v.as_bool())
+            {
+                config.follow_external_changes = value;
+            }
+
+            // Lux to brightness curve
+            if let Some(value) = table.get("mapping_mode").and_then(|v| v.as_str()) {
+                config.mapping_mode = MappingMode::from_str(value)?;
+            }
+
+            if let Some(curve) = table.get("curve") {
+                config.curve = parse_curve(curve)?;
+            }
+
+            // Sensor smoothing
+            if let Some(filter) = table.get("filter").and_then(|v| v.as_table()) {
+                config.filter = parse_filter(filter)?;
+            }
+
+            // Named profiles, applied on demand by `with_profile`
+            if let Some(profiles) = table.get("profiles").and_then(|v| v.as_table()) {
+                for (name, profile) in profiles {
+                    let profile = profile.as_table().ok_or_else(|| {
+                        LumdError::InvalidData(format!("profiles.{} must be a table", name))
+                    })?;
+                    config.profiles.insert(name.clone(), profile.clone());
+                }
+            }
+
+            config.raw = table.clone();
+            config.raw.remove("profiles");
         }
 
         Ok(config)
     }
 }
+
+// Recursively merge `overlay` into `base`; tables merge, everything else is replaced
+fn merge_tables(base: &mut toml::Table, overlay: &toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+// Parse a `[curve]` section of the form `points = [[lux, percent], ...]`
+fn parse_curve(value: &toml::Value) -> Result<Curve> {
+    let points = value
+        .as_table()
+        .and_then(|t| t.get("points"))
+        .and_then(|v| v.as_array())
+        .ok_or_else(|| {
+            LumdError::InvalidData("Curve section must contain a `points` array".into())
+        })?;
+
+    let mut parsed = Vec::with_capacity(points.len());
+    for point in points {
+        let pair = point.as_array().filter(|p| p.len() == 2).ok_or_else(|| {
+            LumdError::InvalidData(format!(
+                "Curve point must be a [lux, percent] pair, got {:?}",
+                point
+            ))
+        })?;
+
+        let lux = as_number(&pair[0]).ok_or_else(|| {
+            LumdError::InvalidData(format!("Curve point lux is not a number: {:?}", pair[0]))
+        })?;
+        let percent = as_number(&pair[1]).ok_or_else(|| {
+            LumdError::InvalidData(format!(
+                "Curve point brightness is not a number: {:?}",
+                pair[1]
+            ))
+        })?;
+
+        parsed.push(CurvePoint { lux, percent });
+    }
+
+    Curve::new(parsed)
+}
+
+// Parse a `[sensor]` section, keeping defaults for missing keys
+fn parse_sensor(table: &toml::Table) -> Result<SensorConfig> {
+    let mut sensor = SensorConfig::default();
+
+    if let Some(value) = table.get("device").and_then(|v| v.as_str()) {
+        sensor.device = Some(value.to_string());
+    }
+
+    if let Some(value) = table.get("name").and_then(|v| v.as_str()) {
+        sensor.name = Some(value.to_string());
+    }
+
+    if let Some(value) = table.get("clear_coefficient").and_then(as_number) {
+        sensor.clear_coefficient = value;
+    }
+
+    if let Some(value) = table.get("both_coefficient").and_then(as_number) {
+        sensor.both_coefficient = value;
+    }
+
+    if let Some(value) = table.get("trigger").and_then(|v| v.as_str()) {
+        sensor.trigger = Some(value.to_string());
+    }
+
+    Ok(sensor)
+}
+
+// Parse a `[backlight]` table, keeping defaults for missing keys
+fn parse_backlight(table: &toml::Table) -> Result<BacklightConfig> {
+    let mut backlight = BacklightConfig::default();
+
+    if let Some(value) = table.get("device").and_then(|v| v.as_str()) {
+        backlight.device = Some(value.to_string());
+    }
+
+    if let Some(value) = table.get("min_brightness").and_then(|v| v.as_integer()) {
+        backlight.min_brightness = Some(value as i32);
+    }
+
+    if let Some(value) = table.get("max_brightness").and_then(|v| v.as_integer()) {
+        backlight.max_brightness = Some(value as i32);
+    }
+
+    if let Some(value) = table.get("scale").and_then(as_number) {
+        if value < 0.0 {
+            return Err(LumdError::InvalidData(format!(
+                "Backlight scale must not be negative, got {}",
+                value
+            )));
+        }
+        backlight.scale = value;
+    }
+
+    if let Some(value) = table.get("transition_steps").and_then(|v| v.as_integer()) {
+        backlight.transition_steps = Some(value as u32);
+    }
+
+    if let Some(value) = table.get("step_delay_ms").and_then(|v| v.as_integer()) {
+        backlight.step_delay_ms = Some(value as u64);
+    }
+
+    if let Some(value) = table
+        .get("transition_duration_ms")
+        .and_then(|v| v.as_integer())
+    {
+        backlight.transition_duration_ms = Some(value as u64);
+    }
+
+    Ok(backlight)
+}
+
+// Parse an `[adaptation]` section, keeping defaults for missing keys
+fn parse_adaptation(table: &toml::Table) -> Result<AdaptationConfig> {
+    let mut adaptation = AdaptationConfig {
+        // Having the section at all opts in unless it says otherwise
+        enabled: true,
+        ..AdaptationConfig::default()
+    };
+
+    if let Some(value) = table.get("enabled").and_then(|v| v.as_bool()) {
+        adaptation.enabled = value;
+    }
+
+    for (key, field) in [
+        ("rising_secs", &mut adaptation.rising_secs),
+        ("falling_secs", &mut adaptation.falling_secs),
+    ] {
+        if let Some(value) = table.get(key).and_then(as_number) {
+            if value <= 0.0 {
+                return Err(LumdError::InvalidData(format!(
+                    "adaptation.{} must be positive, got {}",
+                    key, value
+                )));
+            }
+            *field = value;
+        }
+    }
+
+    Ok(adaptation)
+}
+
+// Parse the overrides of a profile table
+fn parse_profile(table: &toml::Table) -> Result<ProfileOverrides> {
+    let mut profile = ProfileOverrides::default();
+
+    if let Some(value) = table.get("brightness_offset").and_then(|v| v.as_integer()) {
+        profile.brightness_offset = Some(value as i32);
+    }
+
+    if let Some(curve) = table.get("curve") {
+        profile.curve = Some(parse_curve(curve)?);
+    }
+
+    if let Some(value) = table.get("max_percent").and_then(as_number) {
+        profile.max_percent = Some(value.clamp(0.0, 100.0));
+    }
+
+    Ok(profile)
+}
+
+// Parse a `[power]` section with optional `[power.ac]` and `[power.battery]` profiles
+fn parse_power(table: &toml::Table) -> Result<PowerConfig> {
+    let mut power = PowerConfig::default();
+
+    if let Some(ac) = table.get("ac").and_then(|v| v.as_table()) {
+        power.ac = parse_profile(ac)?;
+    }
+
+    if let Some(battery) = table.get("battery").and_then(|v| v.as_table()) {
+        power.battery = parse_profile(battery)?;
+    }
+
+    if let Some(value) = table.get("low_battery_percent").and_then(as_number) {
+        power.low_battery_percent = value;
+    }
+
+    if let Some(value) = table.get("low_battery_max_percent").and_then(as_number) {
+        power.low_battery_max_percent = value.clamp(0.0, 100.0);
+    }
+
+    Ok(power)
+}
+
+// Parse a `[schedule]` section, keeping defaults for missing keys
+fn parse_schedule(table: &toml::Table) -> Result<ScheduleConfig> {
+    let mut schedule = ScheduleConfig::default();
+
+    for (key, limit, field) in [
+        ("latitude", 90.0, &mut schedule.latitude),
+        ("longitude", 180.0, &mut schedule.longitude),
+    ] {
+        if let Some(value) = table.get(key).and_then(as_number) {
+            if value.abs() > limit {
+                return Err(LumdError::InvalidData(format!(
+                    "schedule.{} must be within ±{}, got {}",
+                    key, limit, value
+                )));
+            }
+            *field = Some(value as f64);
+        }
+    }
+    if schedule.latitude.is_some() != schedule.longitude.is_some() {
+        return Err(LumdError::InvalidData(
+            "schedule needs both latitude and longitude".into(),
+        ));
+    }
+
+    for (key, field) in [
+        ("day_percent", &mut schedule.day_percent),
+        ("night_percent", &mut schedule.night_percent),
+    ] {
+        if let Some(value) = table.get(key).and_then(as_number) {
+            if !(0.0..=100.0).contains(&value) {
+                return Err(LumdError::InvalidData(format!(
+                    "schedule.{} must be between 0 and 100, got {}",
+                    key, value
+                )));
+            }
+            *field = value;
+        }
+    }
+
+    if let Some(value) = table.get("full_day_elevation").and_then(as_number) {
+        schedule.full_day_elevation = value as f64;
+    }
+
+    if let Some(value) = table.get("blend").and_then(|v| v.as_str()) {
+        schedule.blend = ScheduleBlend::from_str(value)?;
+    }
+
+    if let Some(value) = table.get("bias_weight").and_then(as_number) {
+        schedule.bias_weight = value.clamp(0.0, 1.0);
+    }
+
+    Ok(schedule)
+}
+
+// Parse a `[keyboard]` section, keeping defaults for missing keys
+fn parse_keyboard(table: &toml::Table) -> KeyboardConfig {
+    let mut keyboard = KeyboardConfig {
+        // Having the section at all opts in unless it says otherwise
+        enabled: true,
+        ..KeyboardConfig::default()
+    };
+
+    if let Some(value) = table.get("enabled").and_then(|v| v.as_bool()) {
+        keyboard.enabled = value;
+    }
+
+    if let Some(value) = table.get("device").and_then(|v| v.as_str()) {
+        keyboard.device = value.to_string();
+    }
+
+    if let Some(value) = table.get("on_below_lux").and_then(as_number) {
+        keyboard.on_below_lux = value;
+    }
+
+    if let Some(value) = table.get("off_above_lux").and_then(as_number) {
+        keyboard.off_above_lux = value;
+    }
+
+    if let Some(value) = table.get("brightness").and_then(|v| v.as_integer()) {
+        keyboard.brightness = Some(value as i32);
+    }
+
+    keyboard
+}
+
+// Parse a `[filter]` section, keeping defaults for missing keys
+fn parse_filter(table: &toml::Table) -> Result<FilterConfig> {
+    let mut filter = FilterConfig::default();
+
+    if let Some(value) = table.get("kind").and_then(|v| v.as_str()) {
+        filter.kind = FilterKind::from_str(value)?;
+    }
+
+    if let Some(value) = table.get("window").and_then(|v| v.as_integer()) {
+        if value < 1 {
+            return Err(LumdError::InvalidData(format!(
+                "Filter window must be at least 1, got {}",
+                value
+            )));
+        }
+        filter.window = value as usize;
+    }
+
+    if let Some(value) = table.get("alpha").and_then(as_number) {
+        if !(value > 0.0 && value <= 1.0) {
+            return Err(LumdError::InvalidData(format!(
+                "Filter alpha must be in (0, 1], got {}",
+                value
+            )));
+        }
+        filter.alpha = value;
+    }
+
+    if let Some(value) = table.get("spike_threshold").and_then(as_number) {
+        filter.spike_threshold = value;
+    }
+
+    if let Some(value) = table.get("spike_duration_ms").and_then(|v| v.as_integer()) {
+        filter.spike_duration_ms = value as u64;
+    }
+
+    Ok(filter)
+}
+
+// Parse a `[hysteresis]` section on top of the thresholds derived so far
+fn parse_hysteresis(table: &toml::Table, base: &HysteresisConfig) -> Result<HysteresisConfig> {
+    let mut hysteresis = base.clone();
+
+    if let Some(value) = table.get("rising") {
+        hysteresis.rising = Threshold::from_value(value)?;
+    }
+
+    if let Some(value) = table.get("falling") {
+        hysteresis.falling = Threshold::from_value(value)?;
+    }
+
+    if let Some(value) = table.get("dwell_ms").and_then(|v| v.as_integer()) {
+        hysteresis.dwell_ms = value as u64;
+    }
+
+    Ok(hysteresis)
+}
+
+// Accept both integer and float TOML values as numbers
+pub fn as_number(value: &toml::Value) -> Option<f32> {
+    value
+        .as_float()
+        .map(|v| v as f32)
+        .or_else(|| value.as_integer().map(|v| v as f32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<Config> {
+        let parsed: toml::Value = toml::from_str(content).unwrap();
+        Config::from_value(&parsed)
+    }
+
+    #[test]
+    fn profile_merges_over_base_settings() {
+        let config = parse(
+            r#"
+            brightness_offset = 40
+            max_percent = 90
+
+            [filter]
+            kind = "median"
+            window = 7
+
+            [profiles.office]
+            brightness_offset = 10
+            filter = { window = 3 }
+            "#,
+        )
+        .unwrap();
+
+        let office = config.with_profile(Some("office")).unwrap();
+        assert_eq!(office.brightness_offset, 10);
+        assert_eq!(office.max_percent, 90.0);
+        // Nested tables merge key by key
+        assert_eq!(office.filter.kind, FilterKind::Median);
+        assert_eq!(office.filter.window, 3);
+        assert!(office.profiles.contains_key("office"));
+
+        // Switching back restores the base settings
+        let base = office.with_profile(None).unwrap();
+        assert_eq!(base.brightness_offset, 40);
+        assert_eq!(base.filter.window, 7);
+    }
+
+    #[test]
+    fn unknown_profile_is_an_error() {
+        let config = parse("brightness_offset = 40").unwrap();
+        assert!(config.with_profile(Some("missing")).is_err());
+    }
+
+    #[test]
+    fn profile_cannot_change_startup_settings() {
+        let config = parse(
+            r#"
+            [profiles.docked]
+            brightness_offset = 10
+            sensor = { device = "iio:device1" }
+            "#,
+        )
+        .unwrap();
+        let err = config.with_profile(Some("docked")).unwrap_err();
+        assert!(err.to_string().contains("sensor"));
+    }
+}
@@ -0,0 +1,309 @@
+use crate::backlight::OutputLimits;
+use crate::mode::Mode;
+use crate::server::{Amount, CommandError, ErrorCode, LumdCommand, Reply};
+use serde_json::json;
+use slog::{Logger, debug, info};
+use std::time::Duration;
+
+// What the next sample should do, as set by commands and the sampler loop
+#[derive(Debug)]
+pub struct Controls {
+    // Offset the outputs were last adjusted with
+    pub offset: i32,
+    // Offset for the next sample, including adjustments made since the last one
+    pub next_offset: i32,
+    pub mode: Mode,
+    // Jump to the next target instead of fading
+    pub instant: bool,
+    // Adjust at the next sample even inside the hysteresis
+    pub force: bool,
+    // The next sample carries a change the user made
+    pub manual: bool,
+    // Nothing is written while the panel is off
+    pub panel_off: bool,
+    // Step for up and down without an amount
+    step: i32,
+    manual_hold: Option<Duration>,
+}
+
+impl Controls {
+    pub fn new(offset: i32, mode: Mode, step: i32, manual_hold: Option<Duration>) -> Self {
+        Self {
+            offset,
+            next_offset: offset,
+            mode,
+            instant: true,
+            force: false,
+            manual: false,
+            panel_off: false,
+            step,
+            manual_hold,
+        }
+    }
+
+    // Take the step and hold time from a new configuration
+    pub fn configure(&mut self, step: i32, manual_hold: Option<Duration>) {
+        self.step = step;
+        self.manual_hold = manual_hold;
+    }
+
+    // Start or extend a manual hold after the user changed the brightness
+    pub fn after_manual(&mut self) {
+        self.mode = self.mode.after_manual(self.manual_hold);
+    }
+
+    // Start collecting commands for the next sample
+    pub fn begin_sample(&mut self) {
+        self.force = false;
+        self.manual = false;
+        self.next_offset = self.offset;
+    }
+
+    // Shift both offsets, e.g. when a profile moves the configured base offset
+    pub fn shift_offset(&mut self, delta: i32) {
+        self.offset += delta;
+        self.next_offset += delta;
+    }
+
+    // Carry out a command that only affects the next sample.
+    // `primary` and `primary_target` describe the first output, which absolute
+    // brightness requests refer to. Returns None for commands the caller handles.
+    pub fn handle(
+        &mut self,
+        log: &Logger,
+        command: &LumdCommand,
+        primary: &OutputLimits,
+        primary_target: i32,
+    ) -> Option<Reply> {
+        let max_brightness = primary.max_brightness;
+        let reply = match *command {
+            LumdCommand::Resample => {
+                info!(log, "Received early resample signal");
+                self.force = true;
+                Ok(json!({}))
+            }
+            LumdCommand::BrightnessUp { step, instant } => self.adjust(instant).map(|()| {
+                let amount = self.step_size(step, max_brightness);
+                self.next_offset += amount;
+                info!(log, "Increasing brightness offset";
+                      "new_offset" => self.next_offset,
+                      "adjustment" => amount);
+                self.adjusted()
+            }),
+            LumdCommand::BrightnessDown { step, instant } => self.adjust(instant).map(|()| {
+                let amount = self.step_size(step, max_brightness);
+                self.next_offset -= amount;
+                info!(log, "Decreasing brightness offset";
+                      "new_offset" => self.next_offset,
+                      "adjustment" => amount);
+                self.adjusted()
+            }),
+            LumdCommand::SetBrightness { value, instant } => self.adjust(instant).map(|()| {
+                // Move the offset by the distance from where the primary output is heading
+                let wanted = value
+                    .to_raw(max_brightness)
+                    .clamp(primary.min.min(primary.max), primary.max);
+                self.next_offset += wanted - primary_target;
+                info!(log, "Setting brightness";
+                      "brightness" => wanted,
+                      "new_offset" => self.next_offset);
+                let mut reply = self.adjusted();
+                reply["target"] = json!(wanted);
+                reply
+            }),
+            LumdCommand::SetOffset { offset, instant } => self.adjust(instant).map(|()| {
+                self.next_offset = offset;
+                info!(log, "Setting brightness offset"; "new_offset" => self.next_offset);
+                self.adjusted()
+            }),
+            LumdCommand::SetMode(next) => {
+                // Going back to auto picks up the current light straight away
+                if next == Mode::Auto && self.mode != Mode::Auto {
+                    self.force = true;
+                }
+                self.mode = next;
+                Ok(json!({ "mode": self.mode.name(), "hold_until": self.mode.hold_until_secs() }))
+            }
+            LumdCommand::LightChanged => {
+                debug!(log, "Ambient light left the threshold window");
+                Ok(json!({}))
+            }
+            LumdCommand::BrightnessChanged => {
+                debug!(log, "Backlight changed outside lumd");
+                Ok(json!({}))
+            }
+            _ => return None,
+        };
+        Some(reply)
+    }
+
+    fn step_size(&self, step: Option<Amount>, max_brightness: i32) -> i32 {
+        step.map_or(self.step, |s| s.to_raw(max_brightness))
+    }
+
+    // Refuse adjustments that would be lost, otherwise mark the next sample as manual
+    fn adjust(&mut self, instant: bool) -> Result<(), CommandError> {
+        if self.mode == Mode::Paused {
+            return Err(CommandError::new(ErrorCode::Unavailable, "paused"));
+        }
+        if self.panel_off {
+            return Err(CommandError::new(ErrorCode::Unavailable, "panel is off"));
+        }
+        self.instant = instant;
+        self.manual = true;
+        // A smooth manual change still has to get past the hysteresis
+        if !instant {
+            self.force = true;
+        }
+        self.after_manual();
+        Ok(())
+    }
+
+    // Reply to a manual adjustment
+    fn adjusted(&self) -> serde_json::Value {
+        json!({
+            "offset": self.next_offset,
+            "mode": self.mode.name(),
+            "hold_until": self.mode.hold_until_secs(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::o;
+
+    fn limits() -> OutputLimits {
+        OutputLimits {
+            max_brightness: 1000,
+            min: 10,
+            max: 1000,
+            scale: 1.0,
+            offset_scale: 1.0,
+        }
+    }
+
+    fn log() -> Logger {
+        Logger::root(slog::Discard, o!())
+    }
+
+    fn controls(mode: Mode) -> Controls {
+        Controls::new(0, mode, 50, Some(Duration::from_secs(60)))
+    }
+
+    #[test]
+    fn up_steps_offset_and_holds() {
+        let mut c = controls(Mode::Auto);
+        c.begin_sample();
+        let command = LumdCommand::BrightnessUp {
+            step: None,
+            instant: true,
+        };
+        let reply = c.handle(&log(), &command, &limits(), 500).unwrap().unwrap();
+        assert_eq!(reply["offset"], 50);
+        assert_eq!(reply["mode"], "manual");
+        assert!(c.manual);
+        assert!(matches!(c.mode, Mode::Manual { until: Some(_) }));
+    }
+
+    #[test]
+    fn set_and_offset_enter_manual_hold() {
+        let mut c = controls(Mode::Auto);
+        let command = LumdCommand::SetBrightness {
+            value: Amount::Percent(30.0),
+            instant: false,
+        };
+        let reply = c.handle(&log(), &command, &limits(), 500).unwrap().unwrap();
+        assert_eq!(reply["target"], 300);
+        assert_eq!(reply["offset"], -200);
+        assert_eq!(reply["mode"], "manual");
+        // Smooth changes bypass the hysteresis
+        assert!(c.force);
+
+        let mut c = controls(Mode::Auto);
+        let command = LumdCommand::SetOffset {
+            offset: 25,
+            instant: true,
+        };
+        let reply = c.handle(&log(), &command, &limits(), 500).unwrap().unwrap();
+        assert_eq!(reply["offset"], 25);
+        assert_eq!(reply["mode"], "manual");
+        assert!(matches!(c.mode, Mode::Manual { .. }));
+    }
+
+    #[test]
+    fn set_brightness_respects_limits() {
+        let mut c = controls(Mode::Auto);
+        let command = LumdCommand::SetBrightness {
+            value: Amount::Raw(0),
+            instant: true,
+        };
+        let reply = c.handle(&log(), &command, &limits(), 500).unwrap().unwrap();
+        assert_eq!(reply["target"], 10);
+    }
+
+    #[test]
+    fn adjustments_refused_while_paused() {
+        let mut c = controls(Mode::Paused);
+        c.begin_sample();
+        for command in [
+            LumdCommand::BrightnessUp {
+                step: Some(Amount::Raw(10)),
+                instant: true,
+            },
+            LumdCommand::BrightnessDown {
+                step: None,
+                instant: true,
+            },
+            LumdCommand::SetBrightness {
+                value: Amount::Raw(200),
+                instant: true,
+            },
+            LumdCommand::SetOffset {
+                offset: 5,
+                instant: true,
+            },
+        ] {
+            let err = c
+                .handle(&log(), &command, &limits(), 500)
+                .unwrap()
+                .unwrap_err();
+            assert_eq!(err.code, ErrorCode::Unavailable);
+        }
+        assert_eq!(c.next_offset, 0);
+        assert!(!c.manual);
+        assert_eq!(c.mode, Mode::Paused);
+    }
+
+    #[test]
+    fn adjustments_refused_while_panel_off() {
+        let mut c = controls(Mode::Auto);
+        c.panel_off = true;
+        let command = LumdCommand::BrightnessUp {
+            step: None,
+            instant: true,
+        };
+        let err = c
+            .handle(&log(), &command, &limits(), 500)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::Unavailable);
+        assert_eq!(c.mode, Mode::Auto);
+    }
+
+    #[test]
+    fn returning_to_auto_forces_a_sample() {
+        let mut c = controls(Mode::Paused);
+        c.begin_sample();
+        c.handle(&log(), &LumdCommand::SetMode(Mode::Auto), &limits(), 500)
+            .unwrap()
+            .unwrap();
+        assert!(c.force);
+        assert_eq!(c.mode, Mode::Auto);
+        assert!(
+            c.handle(&log(), &LumdCommand::Status, &limits(), 500)
+                .is_none()
+        );
+    }
+}
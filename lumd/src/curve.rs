@@ -0,0 +1,317 @@
+use crate::device::lerp;
+use crate::error::{LumdError, Result};
+
+// How lux and brightness values are mapped onto each other.
+// `Linear` interpolates everything in raw units. `Logarithmic` interpolates the
+// curve in log-lux space and transitions in log-brightness space, which better
+// matches how the eye perceives light. `Cie` also works in log-lux space but
+// treats curve percentages as CIE 1976 perceived lightness (L*) and converts
+// them to backlight duty, so equal steps look equally large.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MappingMode {
+    #[default]
+    Linear,
+    Logarithmic,
+    Cie,
+}
+
+impl MappingMode {
+    pub fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "linear" => Ok(MappingMode::Linear),
+            "logarithmic" | "log" => Ok(MappingMode::Logarithmic),
+            "cie" | "cie_lightness" => Ok(MappingMode::Cie),
+            _ => Err(LumdError::InvalidData(format!(
+                "Unknown mapping mode: {} (expected linear, logarithmic or cie)",
+                s
+            ))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MappingMode::Linear => "linear",
+            MappingMode::Logarithmic => "logarithmic",
+            MappingMode::Cie => "cie",
+        }
+    }
+
+    // Position of a lux value along the curve's x axis
+    fn lux_axis(&self, lux: f32) -> f32 {
+        match self {
+            MappingMode::Linear => lux,
+            MappingMode::Logarithmic | MappingMode::Cie => (lux.max(0.0) + 1.0).log10(),
+        }
+    }
+
+    // Raw brightness value for a curve percentage
+    pub fn percent_to_brightness(&self, percent: f32, max_brightness: i32) -> i32 {
+        let duty = match self {
+            MappingMode::Cie => lightness_to_duty(percent),
+            MappingMode::Linear | MappingMode::Logarithmic => percent.clamp(0.0, 100.0) / 100.0,
+        };
+        let scaled = duty * (max_brightness as f32);
+        scaled.clamp(1.0, max_brightness as f32) as i32
+    }
+
+    // Curve percentage for a raw brightness value, the inverse of `percent_to_brightness`
+    pub fn brightness_to_percent(&self, brightness: i32, max_brightness: i32) -> f32 {
+        let duty = brightness as f32 / max_brightness as f32;
+        match self {
+            MappingMode::Cie => duty_to_lightness(duty),
+            MappingMode::Linear | MappingMode::Logarithmic => duty.clamp(0.0, 1.0) * 100.0,
+        }
+    }
+
+    // Interpolate between two raw brightness values for transition progress `t`
+    pub fn interpolate(&self, a: f32, b: f32, t: f32, max_brightness: i32) -> f32 {
+        let max = max_brightness as f32;
+        match self {
+            MappingMode::Linear => lerp(a, b, t),
+            MappingMode::Logarithmic => {
+                let v = lerp((a + 1.0).ln(), (b + 1.0).ln(), t);
+                (v.exp() - 1.0).clamp(0.0, max)
+            }
+            MappingMode::Cie => {
+                let la = duty_to_lightness(a / max);
+                let lb = duty_to_lightness(b / max);
+                lightness_to_duty(lerp(la, lb, t)) * max
+            }
+        }
+    }
+}
+
+// Convert CIE L* (0-100) to relative luminance (0-1)
+fn lightness_to_duty(lightness: f32) -> f32 {
+    let l = lightness.clamp(0.0, 100.0);
+    if l > 8.0 {
+        ((l + 16.0) / 116.0).powi(3)
+    } else {
+        l / 903.3
+    }
+}
+
+// Convert relative luminance (0-1) to CIE L* (0-100)
+fn duty_to_lightness(duty: f32) -> f32 {
+    let y = duty.clamp(0.0, 1.0);
+    if y > 0.008856 {
+        116.0 * y.cbrt() - 16.0
+    } else {
+        903.3 * y
+    }
+}
+
+// A single control point of the lux-to-brightness curve
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CurvePoint {
+    pub lux: f32,
+    pub percent: f32,
+}
+
+// Piecewise linear mapping from ambient lux to a brightness percentage.
+// Below the first point and above the last point the curve is flat.
+#[derive(Debug, Clone)]
+pub struct Curve {
+    points: Vec<CurvePoint>,
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        // Equivalent to the original linear mapping: 0 lux is off, 1000 lux is full brightness
+        Self {
+            points: vec![
+                CurvePoint {
+                    lux: 0.0,
+                    percent: 0.0,
+                },
+                CurvePoint {
+                    lux: 1000.0,
+                    percent: 100.0,
+                },
+            ],
+        }
+    }
+}
+
+impl Curve {
+    pub fn new(points: Vec<CurvePoint>) -> Result<Self> {
+        if points.is_empty() {
+            return Err(LumdError::InvalidData(
+                "Curve must contain at least one point".into(),
+            ));
+        }
+
+        for point in &points {
+            if !point.lux.is_finite() || point.lux < 0.0 {
+                return Err(LumdError::InvalidData(format!(
+                    "Curve point lux must be a non-negative number, got {}",
+                    point.lux
+                )));
+            }
+            if !(0.0..=100.0).contains(&point.percent) {
+                return Err(LumdError::InvalidData(format!(
+                    "Curve point brightness must be between 0 and 100 percent, got {}",
+                    point.percent
+                )));
+            }
+        }
+
+        for pair in points.windows(2) {
+            if pair[1].lux <= pair[0].lux {
+                return Err(LumdError::InvalidData(format!(
+                    "Curve points must be in strictly increasing lux order ({} follows {})",
+                    pair[1].lux, pair[0].lux
+                )));
+            }
+        }
+
+        Ok(Self { points })
+    }
+
+    pub fn points(&self) -> &[CurvePoint] {
+        &self.points
+    }
+
+    // Curve percentage (0-100) for the given lux value
+    pub fn percent_at(&self, lux: f32, mode: MappingMode) -> f32 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+
+        if lux <= first.lux {
+            return first.percent;
+        }
+        if lux >= last.lux {
+            return last.percent;
+        }
+
+        let x = mode.lux_axis(lux);
+        for pair in self.points.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            if lux <= b.lux {
+                let (xa, xb) = (mode.lux_axis(a.lux), mode.lux_axis(b.lux));
+                let t = (x - xa) / (xb - xa);
+                return lerp(a.percent, b.percent, t);
+            }
+        }
+
+        last.percent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve(points: &[(f32, f32)]) -> Result<Curve> {
+        Curve::new(
+            points
+                .iter()
+                .map(|&(lux, percent)| CurvePoint { lux, percent })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn rejects_invalid_points() {
+        assert!(curve(&[]).is_err());
+        assert!(curve(&[(-1.0, 10.0)]).is_err());
+        assert!(curve(&[(f32::NAN, 10.0)]).is_err());
+        assert!(curve(&[(10.0, 101.0)]).is_err());
+        // Lux must strictly increase
+        assert!(curve(&[(0.0, 10.0), (100.0, 50.0), (100.0, 60.0)]).is_err());
+        assert!(curve(&[(0.0, 10.0), (100.0, 50.0), (50.0, 60.0)]).is_err());
+        assert!(curve(&[(0.0, 10.0), (100.0, 50.0), (1000.0, 100.0)]).is_ok());
+    }
+
+    #[test]
+    fn flat_outside_and_interpolated_inside() {
+        let c = curve(&[(10.0, 20.0), (100.0, 60.0)]).unwrap();
+        assert_eq!(c.percent_at(0.0, MappingMode::Linear), 20.0);
+        assert_eq!(c.percent_at(5000.0, MappingMode::Linear), 60.0);
+        assert_eq!(c.percent_at(55.0, MappingMode::Linear), 40.0);
+        // Log-lux interpolation rises faster at the dark end
+        assert!(c.percent_at(55.0, MappingMode::Logarithmic) > 40.0);
+    }
+
+    #[test]
+    fn increasing_points_give_a_monotonic_curve() {
+        let c = curve(&[(0.0, 5.0), (20.0, 30.0), (300.0, 70.0), (5000.0, 100.0)]).unwrap();
+        for mode in [
+            MappingMode::Linear,
+            MappingMode::Logarithmic,
+            MappingMode::Cie,
+        ] {
+            let mut previous = 0.0;
+            for step in 0..=600 {
+                let lux = 10f32.powf(step as f32 / 100.0) - 1.0;
+                let percent = c.percent_at(lux, mode);
+                assert!(percent >= previous, "{:?} at {} lux", mode, lux);
+                previous = percent;
+            }
+        }
+    }
+
+    #[test]
+    fn parses_mapping_modes() {
+        assert_eq!(
+            MappingMode::from_str("linear").unwrap(),
+            MappingMode::Linear
+        );
+        assert_eq!(
+            MappingMode::from_str("Log").unwrap(),
+            MappingMode::Logarithmic
+        );
+        assert_eq!(
+            MappingMode::from_str("cie_lightness").unwrap(),
+            MappingMode::Cie
+        );
+        assert!(MappingMode::from_str("gamma").is_err());
+    }
+
+    #[test]
+    fn cie_mode_darkens_the_middle_of_the_curve() {
+        let linear = MappingMode::Linear.percent_to_brightness(50.0, 1000);
+        let cie = MappingMode::Cie.percent_to_brightness(50.0, 1000);
+        assert_eq!(linear, 500);
+        // 50% lightness is only about 18% of the backlight's light output
+        assert!((170..=190).contains(&cie), "{}", cie);
+        assert_eq!(MappingMode::Cie.percent_to_brightness(100.0, 1000), 1000);
+    }
+
+    #[test]
+    fn brightness_to_percent_inverts_percent_to_brightness() {
+        for mode in [
+            MappingMode::Linear,
+            MappingMode::Logarithmic,
+            MappingMode::Cie,
+        ] {
+            for percent in [10.0, 35.0, 50.0, 80.0, 100.0] {
+                let brightness = mode.percent_to_brightness(percent, 10000);
+                let back = mode.brightness_to_percent(brightness, 10000);
+                assert!(
+                    (back - percent).abs() < 0.1,
+                    "{:?} {} -> {}",
+                    mode,
+                    percent,
+                    back
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn transitions_stay_between_their_ends() {
+        for mode in [
+            MappingMode::Linear,
+            MappingMode::Logarithmic,
+            MappingMode::Cie,
+        ] {
+            assert!((mode.interpolate(100.0, 800.0, 0.0, 1000) - 100.0).abs() < 0.5);
+            assert!((mode.interpolate(100.0, 800.0, 1.0, 1000) - 800.0).abs() < 0.5);
+            let mid = mode.interpolate(100.0, 800.0, 0.5, 1000);
+            assert!(mid > 100.0 && mid < 800.0, "{:?} gave {}", mode, mid);
+        }
+        // Log-brightness steps spend longer at the dark end
+        assert!(MappingMode::Logarithmic.interpolate(100.0, 800.0, 0.5, 1000) < 450.0);
+    }
+}
@@ -1,41 +1,383 @@
+use crate::backlight::BacklightConfig;
 use crate::error::{LumdError, Result};
+use crate::sensor::SensorConfig;
+use slog::{Logger, debug, info, warn};
 use std::{
     fs,
     path::{Path, PathBuf},
 };
 
-pub fn find_backlight_device() -> Result<PathBuf> {
-    let base = PathBuf::from("/sys/class/backlight/");
-    for entry in fs::read_dir(&base)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.join("brightness").exists() && path.join("max_brightness").exists() {
-            return Ok(path);
+// Sorted directory entries of a sysfs class or bus directory
+fn list_devices(base: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(base)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn device_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn read_attribute(path: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(path.join(name))
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+// Preference order of the kernel backlight `type` attribute, lower is better
+fn backlight_type_rank(kind: Option<&str>) -> u8 {
+    match kind {
+        Some("firmware") => 0,
+        Some("platform") => 1,
+        Some("raw") => 2,
+        _ => 3,
+    }
+}
+
+// Backlights selected by one `[[backlight]]` entry: every device matching its
+// `device` glob, or the best ranked device when none is pinned
+pub fn find_backlight_devices(log: &Logger, backlight: &BacklightConfig) -> Result<Vec<PathBuf>> {
+    let mut candidates = Vec::new();
+    for path in list_devices(Path::new("/sys/class/backlight/"))? {
+        let name = device_name(&path);
+        let kind = read_attribute(&path, "type");
+
+        if !(path.join("brightness").exists() && path.join("max_brightness").exists()) {
+            debug!(log, "Skipping backlight candidate";
+                "device" => &name,
+                "reason" => "no brightness controls");
+            continue;
         }
+
+        if let Some(pattern) = &backlight.device
+            && !glob_match(pattern, &name)
+        {
+            info!(log, "Skipping backlight candidate";
+                "device" => &name,
+                "type" => kind.as_deref().unwrap_or("unknown"),
+                "reason" => format!("does not match device = {:?}", pattern));
+            continue;
+        }
+
+        info!(log, "Backlight candidate";
+            "device" => &name,
+            "type" => kind.as_deref().unwrap_or("unknown"));
+        candidates.push((backlight_type_rank(kind.as_deref()), name, path));
     }
-    Err(LumdError::DeviceNotFound(
-        "No backlight device found".into(),
-    ))
+
+    // Stable sort keeps name order between devices of the same type
+    candidates.sort_by_key(|(rank, _, _)| *rank);
+    if backlight.device.is_none() {
+        candidates.truncate(1);
+    }
+    if candidates.is_empty() {
+        return Err(LumdError::DeviceNotFound(
+            "No backlight device found".into(),
+        ));
+    }
+
+    let reason = if backlight.device.is_some() {
+        "matches configured device"
+    } else {
+        "best ranked type (firmware > platform > raw)"
+    };
+    Ok(candidates
+        .into_iter()
+        .map(|(_, name, path)| {
+            info!(log, "Selected backlight device"; "device" => name, "reason" => reason);
+            path
+        })
+        .collect())
+}
+
+// Whether the panel behind a backlight is powered and unblanked, judged by the
+// backlight's `bl_power` and its DRM connector's `dpms`. Unknown counts as on.
+pub fn panel_powered(backlight_path: &Path) -> bool {
+    // Anything but FB_BLANK_UNBLANK means the panel is powered down
+    if read_attribute(backlight_path, "bl_power").is_some_and(|p| p != "0") {
+        return false;
+    }
+
+    // Native backlights sit below their connector, firmware ones need a guess
+    let connectors = match fs::canonicalize(backlight_path)
+        .ok()
+        .and_then(|p| p.parent().map(Path::to_path_buf))
+    {
+        Some(parent) if parent.join("dpms").exists() => vec![parent],
+        _ => internal_connectors(),
+    };
+    connectors
+        .iter()
+        .filter_map(|c| read_attribute(c, "dpms"))
+        .all(|dpms| dpms == "On")
+}
+
+// Connected built-in panel connectors, e.g. "card0-eDP-1"
+fn internal_connectors() -> Vec<PathBuf> {
+    list_devices(Path::new("/sys/class/drm/"))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|path| {
+            let name = device_name(path);
+            ["-eDP-", "-LVDS-", "-DSI-"]
+                .iter()
+                .any(|kind| name.contains(kind))
+                && read_attribute(path, "status").as_deref() == Some("connected")
+        })
+        .collect()
 }
 
-pub fn find_illuminance_device() -> Result<PathBuf> {
-    let base = Path::new("/sys/bus/iio/devices/");
-    for entry in fs::read_dir(base)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_dir() {
-            let raw = path.join("in_illuminance_raw");
-            let scale = path.join("in_illuminance_scale");
-            if raw.exists() && scale.exists() {
-                return Ok(path);
+// LED class devices (e.g. keyboard backlights) whose name matches `pattern`
+pub fn find_led_devices(log: &Logger, pattern: &str) -> Result<Vec<PathBuf>> {
+    let mut leds = Vec::new();
+    for path in list_devices(Path::new("/sys/class/leds/"))? {
+        let name = device_name(&path);
+        if !glob_match(pattern, &name) {
+            continue;
+        }
+        if !(path.join("brightness").exists() && path.join("max_brightness").exists()) {
+            debug!(log, "Skipping LED candidate";
+                "device" => &name,
+                "reason" => "no brightness controls");
+            continue;
+        }
+        info!(log, "Selected LED device"; "device" => &name);
+        leds.push(path);
+    }
+    Ok(leds)
+}
+
+pub fn find_illuminance_device(
+    log: &Logger,
+    sensor: &SensorConfig,
+) -> Result<(PathBuf, LightChannel)> {
+    for path in list_devices(Path::new("/sys/bus/iio/devices/"))? {
+        if !path.is_dir() {
+            continue;
+        }
+        let name = device_name(&path);
+        let iio_name = read_attribute(&path, "name").unwrap_or_default();
+
+        let Some(mut channel) = LightChannel::detect(&path, sensor) else {
+            debug!(log, "Skipping IIO device";
+                "device" => &name,
+                "name" => &iio_name,
+                "reason" => "no light channel");
+            continue;
+        };
+
+        let mismatch = match (&sensor.device, &sensor.name) {
+            (Some(pattern), _) if !glob_match(pattern, &name) => {
+                Some(format!("does not match device = {:?}", pattern))
+            }
+            (_, Some(pattern)) if !glob_match(pattern, &iio_name) => {
+                Some(format!("does not match name = {:?}", pattern))
             }
+            _ => None,
+        };
+        if let Some(reason) = mismatch {
+            info!(log, "Skipping illuminance candidate";
+                "device" => &name,
+                "name" => &iio_name,
+                "channel" => &channel.prefix,
+                "reason" => reason);
+            continue;
         }
+
+        let reason = if sensor.device.is_some() || sensor.name.is_some() {
+            "matches configured device"
+        } else {
+            "first device with a light channel"
+        };
+        if !channel.calibrate(&path)? {
+            warn!(log, "Light channel has no scale, using raw counts as lux";
+                "channel" => &channel.prefix);
+        }
+        info!(log, "Selected illuminance device";
+            "device" => &name,
+            "name" => &iio_name,
+            "channel" => &channel.prefix,
+            "scale" => channel.scale,
+            "offset" => channel.offset,
+            "reason" => reason);
+        return Ok((path, channel));
     }
     Err(LumdError::DeviceNotFound(
         "No IIO illuminance device found".into(),
     ))
 }
 
+// Shell-style wildcard match supporting `*` and `?`
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern and the text index it matched up to
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// How lux is read from an IIO light sensor channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct LightChannel {
+    // Attribute prefix, e.g. "in_illuminance", "in_illuminance0" or "in_intensity_clear"
+    pub prefix: String,
+    // The channel reports lux directly through `_input` instead of `_raw` counts
+    pub processed: bool,
+    // Factor converting the scaled channel value to lux (1.0 for illuminance channels)
+    pub coefficient: f32,
+    // `_scale` and `_offset` of a raw channel, read once by `calibrate`
+    pub scale: f32,
+    pub offset: f32,
+}
+
+impl LightChannel {
+    // Pick the best light channel a device exposes, preferring processed illuminance,
+    // then raw illuminance, then clear/both intensity with the configured coefficients
+    pub fn detect(path: &Path, sensor: &SensorConfig) -> Option<Self> {
+        let mut names: Vec<String> = fs::read_dir(path)
+            .ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+
+        let illuminance = |suffix: &str| {
+            names.iter().find_map(|name| {
+                let prefix = name.strip_suffix(suffix)?;
+                let index = prefix.strip_prefix("in_illuminance")?;
+                index
+                    .chars()
+                    .all(|c| c.is_ascii_digit())
+                    .then(|| prefix.to_string())
+            })
+        };
+
+        if let Some(prefix) = illuminance("_input") {
+            return Some(Self {
+                prefix,
+                processed: true,
+                coefficient: 1.0,
+                scale: 1.0,
+                offset: 0.0,
+            });
+        }
+
+        if let Some(prefix) = illuminance("_raw") {
+            return Some(Self {
+                prefix,
+                processed: false,
+                coefficient: 1.0,
+                scale: 1.0,
+                offset: 0.0,
+            });
+        }
+
+        for (prefix, coefficient) in [
+            ("in_intensity_clear", sensor.clear_coefficient),
+            ("in_intensity_both", sensor.both_coefficient),
+        ] {
+            if names.iter().any(|n| *n == format!("{}_raw", prefix)) {
+                return Some(Self {
+                    prefix: prefix.to_string(),
+                    processed: false,
+                    coefficient,
+                    scale: 1.0,
+                    offset: 0.0,
+                });
+            }
+        }
+
+        None
+    }
+
+    // Channel type shared by all indices/modifiers, e.g. "in_illuminance" for "in_illuminance0"
+    fn shared_prefix(&self) -> &str {
+        if self.prefix.starts_with("in_intensity") {
+            "in_intensity"
+        } else {
+            "in_illuminance"
+        }
+    }
+
+    // Look up a channel attribute, falling back to the value shared by the channel type
+    fn attribute(&self, path: &Path, suffix: &str) -> Option<PathBuf> {
+        [self.prefix.as_str(), self.shared_prefix()]
+            .iter()
+            .map(|prefix| path.join(format!("{}_{}", prefix, suffix)))
+            .find(|p| p.exists())
+    }
+
+    // Read the channel's scale and offset once, so samples only read the value.
+    // Returns false for a raw channel without a scale, whose counts are then used as lux.
+    pub fn calibrate(&mut self, path: &Path) -> Result<bool> {
+        if self.processed {
+            return Ok(true);
+        }
+        self.offset = match self.attribute(path, "offset") {
+            Some(p) => read_f32(&p)?,
+            None => 0.0,
+        };
+        let Some(p) = self.attribute(path, "scale") else {
+            self.scale = 1.0;
+            return Ok(false);
+        };
+        let scale = read_f32(&p)?;
+        if !(scale.is_finite() && scale > 0.0) {
+            return Err(LumdError::InvalidData(format!(
+                "{} is {}, expected a positive number",
+                p.display(),
+                scale
+            )));
+        }
+        self.scale = scale;
+        Ok(true)
+    }
+
+    // Convert a channel value to lux
+    pub fn value_to_lux(&self, value: f32) -> f32 {
+        (value + self.offset) * self.scale * self.coefficient
+    }
+
+    // Inverse of `value_to_lux`, used to program thresholds in channel units
+    pub fn lux_to_value(&self, lux: f32) -> f32 {
+        let factor = self.scale * self.coefficient;
+        if factor > 0.0 {
+            lux / factor - self.offset
+        } else {
+            lux - self.offset
+        }
+    }
+
+    pub fn read_lux(&self, path: &Path) -> Result<f32> {
+        let suffix = if self.processed { "input" } else { "raw" };
+        let value = read_f32(&path.join(format!("{}_{}", self.prefix, suffix)))?;
+        Ok(self.value_to_lux(value))
+    }
+}
+
 pub fn read_f32(path: &Path) -> Result<f32> {
     let s = fs::read_to_string(path)?;
     s.trim().parse().map_err(LumdError::ParseFloat)
@@ -46,12 +388,6 @@ pub fn read_i32(path: &Path) -> Result<i32> {
     s.trim().parse().map_err(LumdError::Parse)
 }
 
-pub fn read_lux(iio_path: &Path) -> Result<f32> {
-    let raw = read_i32(&iio_path.join("in_illuminance_raw"))?;
-    let scale = read_f32(&iio_path.join("in_illuminance_scale"))?;
-    Ok((raw as f32) * scale)
-}
-
 pub fn read_max_brightness(iio_path: &Path) -> Result<i32> {
     read_i32(&iio_path.join("max_brightness"))
 }
@@ -66,11 +402,103 @@ pub fn read_brightness(backlight_path: &Path) -> Result<i32> {
     read_i32(&backlight_path.join("brightness"))
 }
 
-pub fn lux_to_brightness(lux: f32, max_brightness: i32) -> i32 {
-    let scaled = (lux / 1000.0) * (max_brightness as f32);
-    scaled.clamp(1.0, max_brightness as f32) as i32
-}
-
 pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
     a + (b - a) * t.clamp(0.0, 1.0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    // Sensor directory holding the given attributes
+    fn channel_dir(name: &str, files: &[(&str, &str)]) -> TempDir {
+        let dir = TempDir::new(name);
+        for (file, contents) in files {
+            fs::write(dir.join(file), contents).unwrap();
+        }
+        dir
+    }
+
+    fn sensor_config() -> SensorConfig {
+        SensorConfig {
+            clear_coefficient: 0.5,
+            ..SensorConfig::default()
+        }
+    }
+
+    #[test]
+    fn detect_prefers_processed_then_raw_illuminance() {
+        let dir = channel_dir(
+            "detect",
+            &[
+                ("in_illuminance_input", "12.5\n"),
+                ("in_illuminance0_raw", "90\n"),
+                ("in_intensity_clear_raw", "80\n"),
+            ],
+        );
+        let channel = LightChannel::detect(&dir, &sensor_config()).unwrap();
+        assert_eq!(channel.prefix, "in_illuminance");
+        assert!(channel.processed);
+        assert_eq!(channel.read_lux(&dir).unwrap(), 12.5);
+
+        fs::remove_file(dir.join("in_illuminance_input")).unwrap();
+        let channel = LightChannel::detect(&dir, &sensor_config()).unwrap();
+        assert_eq!(channel.prefix, "in_illuminance0");
+        assert!(!channel.processed);
+
+        fs::remove_file(dir.join("in_illuminance0_raw")).unwrap();
+        let channel = LightChannel::detect(&dir, &sensor_config()).unwrap();
+        assert_eq!(channel.prefix, "in_intensity_clear");
+        assert_eq!(channel.coefficient, 0.5);
+
+        fs::remove_file(dir.join("in_intensity_clear_raw")).unwrap();
+        assert!(LightChannel::detect(&dir, &sensor_config()).is_none());
+    }
+
+    #[test]
+    fn glob_matches_wildcards() {
+        assert!(glob_match("iio:device*", "iio:device0"));
+        assert!(glob_match("iio:device?", "iio:device1"));
+        assert!(!glob_match("iio:device?", "iio:device10"));
+        assert!(glob_match("*backlight", "intel_backlight"));
+        assert!(glob_match("*::kbd_*", "tpacpi::kbd_backlight"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("als", "als"));
+        assert!(!glob_match("als", "als2"));
+        assert!(!glob_match("a*c", "abcd"));
+        assert!(!glob_match("", "x"));
+    }
+
+    #[test]
+    fn calibrate_caches_scale_and_offset() {
+        let dir = channel_dir(
+            "calibrate",
+            &[
+                ("in_illuminance0_raw", "90\n"),
+                ("in_illuminance_scale", "0.5\n"),
+                ("in_illuminance0_offset", "10\n"),
+            ],
+        );
+        let mut channel = LightChannel::detect(&dir, &sensor_config()).unwrap();
+        assert_eq!(channel.prefix, "in_illuminance0");
+        assert!(channel.calibrate(&dir).unwrap());
+
+        // Later changes to the attributes are not picked up per sample
+        fs::write(dir.join("in_illuminance_scale"), "100").unwrap();
+        assert_eq!(channel.read_lux(&dir).unwrap(), 50.0);
+        assert_eq!(channel.lux_to_value(50.0), 90.0);
+    }
+
+    #[test]
+    fn raw_channel_without_scale_reads_counts() {
+        let dir = channel_dir("noscale", &[("in_intensity_clear_raw", "80\n")]);
+        let mut channel = LightChannel::detect(&dir, &sensor_config()).unwrap();
+        assert!(!channel.calibrate(&dir).unwrap());
+        assert_eq!(channel.read_lux(&dir).unwrap(), 40.0);
+
+        fs::write(dir.join("in_intensity_scale"), "0").unwrap();
+        assert!(channel.calibrate(&dir).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use crate::device::LightChannel;
+use crate::error::{LumdError, Result};
+use crate::server::{LumdCommand, Request};
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use slog::{Logger, debug, error, info};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Read,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+    },
+    thread,
+};
+
+// IIO_GET_EVENT_FD_IOCTL from linux/iio/events.h
+nix::ioctl_read!(iio_get_event_fd, b'i', 0x90, nix::libc::c_int);
+
+// Size of struct iio_event_data { u64 id; s64 timestamp; }
+const IIO_EVENT_SIZE: usize = 16;
+
+// How lumd learns about ambient light changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SensingMode {
+    // Read the sensor every `sample_interval_secs`
+    #[default]
+    Poll,
+    // Sleep until the sensor reports leaving a lux window
+    Events,
+}
+
+impl SensingMode {
+    pub fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "poll" => Ok(SensingMode::Poll),
+            "events" => Ok(SensingMode::Events),
+            _ => Err(LumdError::InvalidData(format!(
+                "Unknown sensing mode: {} (expected poll or events)",
+                s
+            ))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SensingMode::Poll => "poll",
+            SensingMode::Events => "events",
+        }
+    }
+}
+
+// Threshold event configuration for an IIO light sensor.
+// lumd programs a window around the current lux and a watcher thread wakes the
+// sampler when the kernel reports the light leaving it.
+pub struct IioEvents {
+    events_dir: PathBuf,
+    channel: LightChannel,
+    window_percent: f32,
+    event_file: Option<File>,
+}
+
+impl IioEvents {
+    // Set up threshold events, failing if the driver does not support them.
+    // `device` is the already open character device when the buffer backend holds it,
+    // it can not be opened a second time.
+    pub fn open(
+        iio_path: &Path,
+        channel: &LightChannel,
+        window_percent: f32,
+        device: Option<BorrowedFd<'_>>,
+    ) -> Result<Self> {
+        let events_dir = iio_path.join("events");
+        for direction in ["rising", "falling"] {
+            let name = format!("{}_thresh_{}_value", channel.prefix, direction);
+            if !events_dir.join(&name).exists() {
+                return Err(LumdError::DeviceNotFound(format!(
+                    "Sensor has no {} event attribute",
+                    name
+                )));
+            }
+        }
+
+        let opened;
+        let dev = match device {
+            Some(fd) => fd,
+            None => {
+                let device_name = iio_path
+                    .file_name()
+                    .ok_or_else(|| LumdError::DeviceNotFound("Invalid IIO device path".into()))?;
+                let dev_path = Path::new("/dev").join(device_name);
+                opened = OpenOptions::new().read(true).open(&dev_path)?;
+                opened.as_fd()
+            }
+        };
+
+        let mut fd: nix::libc::c_int = -1;
+        // SAFETY: the ioctl writes a single c_int to `fd` on success
+        unsafe { iio_get_event_fd(dev.as_raw_fd(), &mut fd) }
+            .map_err(|e| LumdError::Io(std::io::Error::from(e)))?;
+        // SAFETY: the kernel handed us a new, owned file descriptor
+        let event_file = unsafe { File::from_raw_fd(fd) };
+
+        let events = Self {
+            events_dir,
+            channel: channel.clone(),
+            window_percent,
+            event_file: Some(event_file),
+        };
+        events.enable()?;
+        Ok(events)
+    }
+
+    fn enable(&self) -> Result<()> {
+        let either = self.attribute("either_en");
+        if either.exists() {
+            fs::write(either, "1")?;
+            return Ok(());
+        }
+        for name in ["rising_en", "falling_en"] {
+            let path = self.attribute(name);
+            if path.exists() {
+                fs::write(path, "1")?;
+            }
+        }
+        Ok(())
+    }
+
+    // Path of a threshold event attribute for this channel, e.g. `rising_value`
+    fn attribute(&self, name: &str) -> PathBuf {
+        self.events_dir
+            .join(format!("{}_thresh_{}", self.channel.prefix, name))
+    }
+
+    // Program the thresholds to a window of `window_percent` around `lux`
+    pub fn set_window(&self, lux: f32) -> Result<()> {
+        let raw = self.channel.lux_to_value(lux);
+        let delta = (raw * self.window_percent / 100.0).max(1.0);
+        let rising = (raw + delta).ceil() as i64;
+        let falling = (raw - delta).floor().max(0.0) as i64;
+
+        fs::write(self.attribute("falling_value"), falling.to_string())?;
+        fs::write(self.attribute("rising_value"), rising.to_string())?;
+        Ok(())
+    }
+
+    // Start a thread that sends `LumdCommand::LightChanged` on every threshold event
+    pub fn spawn_watcher(
+        &mut self,
+        log: Logger,
+        tx: Sender<Request>,
+        running: Arc<AtomicBool>,
+    ) -> Result<()> {
+        let mut file = self
+            .event_file
+            .take()
+            .ok_or_else(|| LumdError::Communication("Event watcher already running".into()))?;
+
+        thread::spawn(move || {
+            info!(log, "Watching for light threshold events");
+            let mut buf = [0u8; IIO_EVENT_SIZE];
+            while running.load(Ordering::SeqCst) {
+                let mut fds = [PollFd::new(file.as_fd(), PollFlags::POLLIN)];
+                match poll(&mut fds, PollTimeout::from(1000u16)) {
+                    Ok(0) => continue,
+                    Ok(_) => {}
+                    Err(nix::errno::Errno::EINTR) => continue,
+                    Err(e) => {
+                        error!(log, "Polling event fd failed"; "error" => %e);
+                        break;
+                    }
+                }
+
+                if let Err(e) = file.read_exact(&mut buf) {
+                    error!(log, "Reading light event failed"; "error" => %e);
+                    break;
+                }
+                debug!(log, "Light threshold event received");
+                if tx.send(LumdCommand::LightChanged.into()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn threshold(events: &IioEvents, name: &str) -> i64 {
+        fs::read_to_string(events.attribute(name))
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn window_is_programmed_in_channel_units() {
+        let dir = TempDir::new("events");
+        // Threshold attributes in a scratch directory, without an event fd
+        let events = IioEvents {
+            events_dir: dir.to_path_buf(),
+            channel: LightChannel {
+                prefix: "in_illuminance".into(),
+                processed: false,
+                coefficient: 1.0,
+                scale: 0.5,
+                offset: 0.0,
+            },
+            window_percent: 10.0,
+            event_file: None,
+        };
+
+        // 100 lux is 200 counts at a scale of 0.5
+        events.set_window(100.0).unwrap();
+        assert_eq!(threshold(&events, "rising_value"), 220);
+        assert_eq!(threshold(&events, "falling_value"), 180);
+
+        // In the dark the window never collapses below one count or goes negative
+        events.set_window(0.0).unwrap();
+        assert_eq!(threshold(&events, "rising_value"), 1);
+        assert_eq!(threshold(&events, "falling_value"), 0);
+    }
+}
@@ -0,0 +1,199 @@
+use crate::error::{LumdError, Result};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Smoothing applied to raw lux samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilterKind {
+    #[default]
+    None,
+    MovingAverage,
+    Ema,
+    Median,
+}
+
+impl FilterKind {
+    pub fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(FilterKind::None),
+            "moving_average" | "average" => Ok(FilterKind::MovingAverage),
+            "ema" => Ok(FilterKind::Ema),
+            "median" => Ok(FilterKind::Median),
+            _ => Err(LumdError::InvalidData(format!(
+                "Unknown filter kind: {} (expected none, moving_average, ema or median)",
+                s
+            ))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterKind::None => "none",
+            FilterKind::MovingAverage => "moving_average",
+            FilterKind::Ema => "ema",
+            FilterKind::Median => "median",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FilterConfig {
+    pub kind: FilterKind,
+    // Number of samples for moving average and median
+    pub window: usize,
+    // Smoothing factor for EMA, 0 < alpha <= 1
+    pub alpha: f32,
+    // Relative change from the filtered value that counts as a possible spike
+    pub spike_threshold: f32,
+    // How long a change must persist before it is accepted, 0 disables spike rejection
+    pub spike_duration_ms: u64,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            kind: FilterKind::None,
+            window: 5,
+            alpha: 0.3,
+            spike_threshold: 0.5,
+            spike_duration_ms: 0,
+        }
+    }
+}
+
+// Stateful filter between the light sensor and the brightness logic
+#[derive(Debug)]
+pub struct LuxFilter {
+    config: FilterConfig,
+    history: VecDeque<f32>,
+    last: Option<f32>,
+    // When the current out-of-range excursion started
+    pending_since: Option<Instant>,
+}
+
+impl LuxFilter {
+    pub fn new(config: FilterConfig) -> Self {
+        Self {
+            config,
+            history: VecDeque::new(),
+            last: None,
+            pending_since: None,
+        }
+    }
+
+    // True while a possible spike is held back
+    pub fn is_holding(&self) -> bool {
+        self.pending_since.is_some()
+    }
+
+    // Filtered lux value of the last sample
+    pub fn last(&self) -> Option<f32> {
+        self.last
+    }
+
+    // Feed a raw sample and return the filtered lux value
+    pub fn apply(&mut self, raw: f32) -> f32 {
+        let now = Instant::now();
+
+        if let Some(last) = self.last
+            && self.config.spike_duration_ms > 0
+        {
+            let deviation = (raw - last).abs() / last.max(1.0);
+            if deviation > self.config.spike_threshold {
+                let since = *self.pending_since.get_or_insert(now);
+                if now.duration_since(since) < Duration::from_millis(self.config.spike_duration_ms)
+                {
+                    // Hold the previous value until the change proves to be real
+                    return last;
+                }
+                // The change persisted, jump to the new level instead of averaging it in
+                self.history.clear();
+            }
+            self.pending_since = None;
+        }
+
+        let window = self.config.window.max(1);
+        self.history.push_back(raw);
+        while self.history.len() > window {
+            self.history.pop_front();
+        }
+
+        let filtered = match self.config.kind {
+            FilterKind::None => raw,
+            FilterKind::MovingAverage => {
+                self.history.iter().sum::<f32>() / self.history.len() as f32
+            }
+            FilterKind::Ema => match self.last {
+                Some(last) if self.history.len() > 1 => {
+                    let alpha = self.config.alpha.clamp(f32::EPSILON, 1.0);
+                    last + alpha * (raw - last)
+                }
+                _ => raw,
+            },
+            FilterKind::Median => {
+                let mut sorted: Vec<f32> = self.history.iter().copied().collect();
+                sorted.sort_by(f32::total_cmp);
+                let mid = sorted.len() / 2;
+                if sorted.len().is_multiple_of(2) {
+                    (sorted[mid - 1] + sorted[mid]) / 2.0
+                } else {
+                    sorted[mid]
+                }
+            }
+        };
+
+        self.last = Some(filtered);
+        filtered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn filter(kind: FilterKind, spike_duration_ms: u64) -> LuxFilter {
+        LuxFilter::new(FilterConfig {
+            kind,
+            window: 3,
+            spike_duration_ms,
+            ..FilterConfig::default()
+        })
+    }
+
+    #[test]
+    fn median_ignores_a_single_outlier() {
+        let mut f = filter(FilterKind::Median, 0);
+        assert_eq!(f.apply(100.0), 100.0);
+        assert_eq!(f.apply(110.0), 105.0);
+        assert_eq!(f.apply(5000.0), 110.0);
+        assert_eq!(f.apply(120.0), 120.0);
+    }
+
+    #[test]
+    fn moving_average_uses_the_window() {
+        let mut f = filter(FilterKind::MovingAverage, 0);
+        for raw in [10.0, 20.0, 30.0] {
+            f.apply(raw);
+        }
+        assert_eq!(f.apply(40.0), 30.0);
+    }
+
+    #[test]
+    fn spike_is_held_until_it_persists() {
+        let mut f = filter(FilterKind::None, 30);
+        assert_eq!(f.apply(100.0), 100.0);
+        assert_eq!(f.apply(1000.0), 100.0);
+        assert!(f.is_holding());
+
+        // A short spike is dropped
+        assert_eq!(f.apply(105.0), 105.0);
+        assert!(!f.is_holding());
+
+        // A lasting change is taken as the new level
+        assert_eq!(f.apply(1000.0), 105.0);
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(f.apply(1000.0), 1000.0);
+        assert!(!f.is_holding());
+    }
+}
@@ -0,0 +1,254 @@
+use crate::error::{LumdError, Result};
+use std::time::{Duration, Instant};
+
+// What a hysteresis threshold is measured in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThresholdUnit {
+    // Absolute change in lux
+    Lux,
+    // Change in lux relative to the level lumd last adjusted for
+    Percent,
+    // Change in raw target brightness (the legacy `brightness_threshold`)
+    Brightness,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Threshold {
+    pub amount: f32,
+    pub unit: ThresholdUnit,
+}
+
+impl Threshold {
+    // Parse `50`, `"50lux"`, `"10%"` or `"8raw"`
+    pub fn from_value(value: &toml::Value) -> Result<Self> {
+        if let Some(amount) = value.as_integer() {
+            return Ok(Self {
+                amount: amount as f32,
+                unit: ThresholdUnit::Lux,
+            });
+        }
+        if let Some(amount) = value.as_float() {
+            return Ok(Self {
+                amount: amount as f32,
+                unit: ThresholdUnit::Lux,
+            });
+        }
+
+        let invalid = || {
+            LumdError::InvalidData(format!(
+                "Threshold must be a lux number or a string like \"50lux\", \"10%\" or \"8raw\", got {:?}",
+                value
+            ))
+        };
+
+        let s = value.as_str().ok_or_else(invalid)?.trim();
+        let (number, unit) = if let Some(n) = s.strip_suffix('%') {
+            (n, ThresholdUnit::Percent)
+        } else if let Some(n) = s.strip_suffix("lux") {
+            (n, ThresholdUnit::Lux)
+        } else if let Some(n) = s.strip_suffix("raw") {
+            (n, ThresholdUnit::Brightness)
+        } else {
+            (s, ThresholdUnit::Lux)
+        };
+
+        let amount: f32 = number.trim().parse().map_err(|_| invalid())?;
+        if amount < 0.0 {
+            return Err(invalid());
+        }
+        Ok(Self { amount, unit })
+    }
+
+    // The threshold as written in the config, e.g. `"10%"`
+    pub fn describe(&self) -> String {
+        let unit = match self.unit {
+            ThresholdUnit::Lux => "lux",
+            ThresholdUnit::Percent => "%",
+            ThresholdUnit::Brightness => "raw",
+        };
+        format!("{}{}", self.amount, unit)
+    }
+
+    fn exceeded(&self, anchor_lux: f32, lux: f32, current: i32, target: i32) -> bool {
+        match self.unit {
+            ThresholdUnit::Lux => (lux - anchor_lux).abs() > self.amount,
+            ThresholdUnit::Percent => {
+                (lux - anchor_lux).abs() / anchor_lux.max(1.0) * 100.0 > self.amount
+            }
+            ThresholdUnit::Brightness => (target - current).abs() as f32 > self.amount,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HysteresisConfig {
+    pub rising: Threshold,
+    pub falling: Threshold,
+    // How long a change must hold before lumd reacts to it
+    pub dwell_ms: u64,
+}
+
+impl HysteresisConfig {
+    // Symmetric brightness threshold with no dwell time, matching the legacy behaviour
+    pub fn from_brightness_threshold(threshold: i32) -> Self {
+        let threshold = Threshold {
+            amount: threshold as f32,
+            unit: ThresholdUnit::Brightness,
+        };
+        Self {
+            rising: threshold,
+            falling: threshold,
+            dwell_ms: 0,
+        }
+    }
+}
+
+// Decides whether a new target is far enough away, for long enough, to act on
+#[derive(Debug)]
+pub struct Hysteresis {
+    config: HysteresisConfig,
+    // Lux at which lumd last committed to a target
+    anchor_lux: Option<f32>,
+    // Start time and direction (true = rising) of a change waiting out the dwell time
+    pending: Option<(Instant, bool)>,
+}
+
+impl Hysteresis {
+    pub fn new(config: HysteresisConfig) -> Self {
+        Self {
+            config,
+            anchor_lux: None,
+            pending: None,
+        }
+    }
+
+    // Returns true when lumd should move from `current` towards `target`
+    pub fn should_adjust(&mut self, lux: f32, current: i32, target: i32) -> bool {
+        if target == current {
+            self.pending = None;
+            return false;
+        }
+
+        let Some(anchor_lux) = self.anchor_lux else {
+            self.accept(lux);
+            return true;
+        };
+
+        let rising = target > current;
+        let threshold = if rising {
+            &self.config.rising
+        } else {
+            &self.config.falling
+        };
+
+        if !threshold.exceeded(anchor_lux, lux, current, target) {
+            self.pending = None;
+            return false;
+        }
+
+        let now = Instant::now();
+        let dwell = Duration::from_millis(self.config.dwell_ms);
+        match self.pending {
+            Some((since, direction))
+                if direction == rising && now.duration_since(since) < dwell =>
+            {
+                return false;
+            }
+            Some((_, direction)) if direction == rising => {}
+            _ if !dwell.is_zero() => {
+                self.pending = Some((now, rising));
+                return false;
+            }
+            _ => {}
+        }
+
+        self.accept(lux);
+        true
+    }
+
+    // True while a change is waiting out the dwell time
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    // Commit to `lux` as the new reference level, e.g. after a forced adjustment
+    pub fn accept(&mut self, lux: f32) {
+        self.anchor_lux = Some(lux);
+        self.pending = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn hysteresis(dwell_ms: u64) -> Hysteresis {
+        Hysteresis::new(HysteresisConfig {
+            rising: Threshold {
+                amount: 50.0,
+                unit: ThresholdUnit::Lux,
+            },
+            falling: Threshold {
+                amount: 20.0,
+                unit: ThresholdUnit::Percent,
+            },
+            dwell_ms,
+        })
+    }
+
+    #[test]
+    fn parses_threshold_units() {
+        let parse = |s: &str| Threshold::from_value(&toml::Value::String(s.into())).unwrap();
+        assert_eq!(parse("10%").unit, ThresholdUnit::Percent);
+        assert_eq!(parse("8raw").unit, ThresholdUnit::Brightness);
+        assert_eq!(parse("50lux").amount, 50.0);
+        assert_eq!(
+            Threshold::from_value(&toml::Value::Integer(30)).unwrap(),
+            Threshold {
+                amount: 30.0,
+                unit: ThresholdUnit::Lux
+            }
+        );
+        assert!(Threshold::from_value(&toml::Value::String("-5%".into())).is_err());
+        assert_eq!(parse("10%").describe(), "10%");
+    }
+
+    #[test]
+    fn thresholds_are_asymmetric() {
+        let mut h = hysteresis(0);
+        assert!(h.should_adjust(100.0, 100, 200));
+        // Rising needs more than 50 lux
+        assert!(!h.should_adjust(140.0, 200, 250));
+        assert!(h.should_adjust(160.0, 200, 250));
+        // Falling needs more than 20% of the anchor
+        assert!(!h.should_adjust(140.0, 250, 200));
+        assert!(h.should_adjust(120.0, 250, 200));
+    }
+
+    #[test]
+    fn dwell_holds_changes_back() {
+        let mut h = hysteresis(30);
+        h.accept(100.0);
+        assert!(!h.should_adjust(200.0, 100, 300));
+        assert!(h.is_pending());
+        assert!(!h.should_adjust(200.0, 100, 300));
+
+        thread::sleep(Duration::from_millis(40));
+        assert!(h.should_adjust(200.0, 100, 300));
+        assert!(!h.is_pending());
+    }
+
+    #[test]
+    fn dwell_restarts_when_the_change_goes_away() {
+        let mut h = hysteresis(30);
+        h.accept(100.0);
+        assert!(!h.should_adjust(200.0, 100, 300));
+        // Back inside the threshold
+        assert!(!h.should_adjust(110.0, 100, 120));
+        assert!(!h.is_pending());
+
+        thread::sleep(Duration::from_millis(40));
+        assert!(!h.should_adjust(200.0, 100, 300));
+    }
+}
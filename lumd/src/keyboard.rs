@@ -0,0 +1,145 @@
+use crate::device::{find_led_devices, read_brightness, read_max_brightness, set_brightness};
+use crate::error::{LumdError, Result};
+use slog::{Logger, info};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone)]
+pub struct KeyboardConfig {
+    pub enabled: bool,
+    // LED class device name, glob allowed
+    pub device: String,
+    // Turn the keyboard backlight on below this lux...
+    pub on_below_lux: f32,
+    // ...and off above this one; readings in between keep the current state
+    pub off_above_lux: f32,
+    // Raw LED brightness when on, defaulting to the LED maximum
+    pub brightness: Option<i32>,
+}
+
+impl Default for KeyboardConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            device: "*::kbd_backlight".into(),
+            on_below_lux: 20.0,
+            off_above_lux: 60.0,
+            brightness: None,
+        }
+    }
+}
+
+// Keyboard backlight LEDs switched on in the dark and off in bright light
+#[derive(Debug)]
+pub struct KeyboardBacklight {
+    leds: Vec<(PathBuf, i32)>,
+    on_below_lux: f32,
+    off_above_lux: f32,
+    lit: Option<bool>,
+}
+
+impl KeyboardBacklight {
+    pub fn open(log: &Logger, config: &KeyboardConfig) -> Result<Self> {
+        let mut leds = Vec::new();
+        for path in find_led_devices(log, &config.device)? {
+            let max_brightness = read_max_brightness(&path)?;
+            let level = config
+                .brightness
+                .unwrap_or(max_brightness)
+                .clamp(0, max_brightness);
+            info!(log, "Managing keyboard backlight";
+                "path" => %path.display(),
+                "max_brightness" => max_brightness,
+                "on_level" => level);
+            leds.push((path, level));
+        }
+
+        if leds.is_empty() {
+            return Err(LumdError::DeviceNotFound(format!(
+                "No keyboard backlight matching {:?}",
+                config.device
+            )));
+        }
+
+        // Start from whatever state the keyboard is in now
+        let lit = read_brightness(&leds[0].0).ok().map(|b| b > 0);
+
+        Ok(Self {
+            leds,
+            on_below_lux: config.on_below_lux,
+            off_above_lux: config.off_above_lux.max(config.on_below_lux),
+            lit,
+        })
+    }
+
+    // Follow the ambient light, switching only when a threshold is crossed
+    pub fn update(&mut self, log: &Logger, lux: f32) -> Result<()> {
+        let want = if lux < self.on_below_lux {
+            true
+        } else if lux > self.off_above_lux {
+            false
+        } else {
+            return Ok(());
+        };
+
+        if self.lit == Some(want) {
+            return Ok(());
+        }
+
+        for (path, level) in &self.leds {
+            set_brightness(path, if want { *level } else { 0 })?;
+        }
+        info!(log, "Switched keyboard backlight";
+            "on" => want,
+            "lux" => format!("{:.1}", lux));
+        self.lit = Some(want);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+    use slog::o;
+
+    #[test]
+    fn switches_only_outside_the_hysteresis_band() {
+        let dir = TempDir::new("keyboard");
+        let mut kbd = KeyboardBacklight {
+            leds: vec![(dir.to_path_buf(), 3)],
+            on_below_lux: 20.0,
+            off_above_lux: 60.0,
+            lit: None,
+        };
+        let log = Logger::root(slog::Discard, o!());
+        let lit = |kbd: &mut KeyboardBacklight, lux: f32| {
+            kbd.update(&log, lux).unwrap();
+            read_brightness(&dir).unwrap()
+        };
+
+        assert_eq!(lit(&mut kbd, 10.0), 3);
+        // Inside the band the keyboard stays as it is
+        assert_eq!(lit(&mut kbd, 40.0), 3);
+        assert_eq!(lit(&mut kbd, 60.0), 3);
+        assert_eq!(lit(&mut kbd, 70.0), 0);
+        assert_eq!(lit(&mut kbd, 20.0), 0);
+        assert_eq!(lit(&mut kbd, 19.0), 3);
+    }
+
+    #[test]
+    fn known_state_is_not_written_again() {
+        let dir = TempDir::new("keyboard");
+        let mut kbd = KeyboardBacklight {
+            leds: vec![(dir.to_path_buf(), 3)],
+            on_below_lux: 20.0,
+            off_above_lux: 60.0,
+            lit: Some(true),
+        };
+        let log = Logger::root(slog::Discard, o!());
+        // Already on, so nothing is written below the on threshold
+        kbd.update(&log, 5.0).unwrap();
+        assert!(!dir.join("brightness").exists());
+        kbd.update(&log, 100.0).unwrap();
+        assert_eq!(read_brightness(&dir).unwrap(), 0);
+    }
+}
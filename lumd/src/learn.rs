@@ -0,0 +1,323 @@
+use crate::backlight::Output;
+use crate::config::{Config, as_number};
+use crate::curve::{Curve, MappingMode};
+use crate::error::{LumdError, Result};
+use crate::status::percent;
+use serde_json::{Value, json};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Samples closer than this (in decades of lux) replace each other
+const MERGE_DECADES: f32 = 0.15;
+// Distance (in decades of lux) over which a correction fades out past the outermost samples
+const FALLOFF_DECADES: f32 = 1.0;
+// Upper bound on stored samples, oldest are dropped first
+const MAX_SAMPLES: usize = 32;
+
+// A brightness the user chose at a given ambient light level
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LearnedSample {
+    pub lux: f32,
+    pub percent: f32,
+}
+
+// Personalised correction to the configured curve, learned from manual adjustments.
+// Each sample pins the curve to the user's chosen percentage at that lux. Between
+// samples the correction is interpolated in log-lux space; past the outermost samples
+// it fades back to the configured curve.
+#[derive(Debug, Clone)]
+pub struct LearnedModel {
+    path: PathBuf,
+    // Kept in insertion order so the oldest sample can be evicted
+    samples: Vec<LearnedSample>,
+}
+
+fn log_lux(lux: f32) -> f32 {
+    (lux.max(0.0) + 1.0).log10()
+}
+
+impl LearnedModel {
+    // Create an empty model that will be saved to `path`
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            samples: Vec::new(),
+        }
+    }
+
+    // Load the model from `path`, starting empty if the file does not exist
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut model = Self::new(path);
+
+        if !model.path.exists() {
+            return Ok(model);
+        }
+
+        let content = fs::read_to_string(&model.path)?;
+        let parsed: toml::Value = toml::from_str(&content)
+            .map_err(|e| LumdError::InvalidData(format!("Learned model parse error: {}", e)))?;
+
+        if let Some(samples) = parsed.get("samples").and_then(|v| v.as_array()) {
+            for sample in samples {
+                let pair = sample.as_array().filter(|p| p.len() == 2);
+                let values = pair.and_then(|p| Some((as_number(&p[0])?, as_number(&p[1])?)));
+                match values {
+                    Some((lux, percent)) => model.samples.push(LearnedSample { lux, percent }),
+                    None => {
+                        return Err(LumdError::InvalidData(format!(
+                            "Learned sample must be a [lux, percent] pair, got {:?}",
+                            sample
+                        )));
+                    }
+                }
+            }
+        }
+
+        Ok(model)
+    }
+
+    // Write the model to disk atomically
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent()
+            && !parent.exists()
+        {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Three decimals, f32 values would otherwise be written as 12.300000190734863
+        let round = |v: f32| toml::Value::Float((v as f64 * 1000.0).round() / 1000.0);
+        let samples: Vec<toml::Value> = self
+            .samples
+            .iter()
+            .map(|s| toml::Value::Array(vec![round(s.lux), round(s.percent)]))
+            .collect();
+        let mut table = toml::Table::new();
+        table.insert("samples".into(), samples.into());
+
+        let content = toml::to_string(&table).map_err(|e| {
+            LumdError::InvalidData(format!("Learned model serialization error: {}", e))
+        })?;
+        let content = format!("# lumd learned brightness preferences\n{}", content);
+
+        let tmp = self.path.with_extension("toml.tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    // Samples ordered by lux
+    pub fn samples(&self) -> Vec<LearnedSample> {
+        let mut samples = self.samples.clone();
+        samples.sort_by(|a, b| a.lux.total_cmp(&b.lux));
+        samples
+    }
+
+    // Record that the user wants `percent` of the curve at `lux`
+    pub fn record(&mut self, lux: f32, percent: f32) {
+        let x = log_lux(lux);
+        self.samples
+            .retain(|s| (log_lux(s.lux) - x).abs() >= MERGE_DECADES);
+        self.samples.push(LearnedSample {
+            lux,
+            percent: percent.clamp(0.0, 100.0),
+        });
+        if self.samples.len() > MAX_SAMPLES {
+            let excess = self.samples.len() - MAX_SAMPLES;
+            self.samples.drain(..excess);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    // Curve percentage at `lux` with the learned correction applied
+    pub fn percent_at(&self, curve: &Curve, lux: f32, mode: MappingMode) -> f32 {
+        let base = curve.percent_at(lux, mode);
+        if self.samples.is_empty() {
+            return base;
+        }
+
+        // Corrections relative to the configured curve, sorted by lux
+        let mut corrections: Vec<(f32, f32)> = self
+            .samples
+            .iter()
+            .map(|s| (log_lux(s.lux), s.percent - curve.percent_at(s.lux, mode)))
+            .collect();
+        corrections.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let x = log_lux(lux);
+        let first = corrections[0];
+        let last = corrections[corrections.len() - 1];
+
+        let correction = if x <= first.0 {
+            first.1 * (1.0 - (first.0 - x) / FALLOFF_DECADES).max(0.0)
+        } else if x >= last.0 {
+            last.1 * (1.0 - (x - last.0) / FALLOFF_DECADES).max(0.0)
+        } else {
+            corrections
+                .windows(2)
+                .find(|pair| x <= pair[1].0)
+                .map(|pair| {
+                    let (a, b) = (pair[0], pair[1]);
+                    a.1 + (b.1 - a.1) * (x - a.0) / (b.0 - a.0)
+                })
+                .unwrap_or(last.1)
+        };
+
+        (base + correction).clamp(0.0, 100.0)
+    }
+
+    // What the `learned` request reports: each learned sample with the brightness
+    // it gives every output once the output's limits, scale and cap are applied
+    pub fn to_json(&self, outputs: &[Output], config: &Config) -> Value {
+        let samples: Vec<Value> = self
+            .samples()
+            .iter()
+            .map(|sample| {
+                let outputs: Vec<Value> = outputs
+                    .iter()
+                    .map(|output| {
+                        let cap = output.limits.cap(config.mapping_mode, config.max_percent);
+                        let brightness = output
+                            .limits
+                            .target(
+                                sample.percent,
+                                config.mapping_mode,
+                                config.brightness_offset,
+                            )
+                            .min(cap);
+                        let max = output.limits.max_brightness;
+                        json!({
+                            "name": output.name,
+                            "brightness": brightness,
+                            "brightness_percent": percent(brightness, max),
+                        })
+                    })
+                    .collect();
+                json!({ "lux": sample.lux, "percent": sample.percent, "outputs": outputs })
+            })
+            .collect();
+
+        json!({
+            "path": self.path.display().to_string(),
+            "enabled": config.learn_preferences,
+            "samples": samples,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curve::CurvePoint;
+    use crate::testutil::TempDir;
+
+    fn curve() -> Curve {
+        Curve::new(vec![
+            CurvePoint {
+                lux: 0.0,
+                percent: 0.0,
+            },
+            CurvePoint {
+                lux: 10000.0,
+                percent: 100.0,
+            },
+        ])
+        .unwrap()
+    }
+
+    // Lux a number of decades away from `lux` on the learned model's axis
+    fn decades_from(lux: f32, decades: f32) -> f32 {
+        10f32.powf(log_lux(lux) + decades) - 1.0
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 0.05
+    }
+
+    #[test]
+    fn nearby_samples_replace_each_other() {
+        let mut model = LearnedModel::new("unused.toml");
+        model.record(100.0, 50.0);
+        model.record(110.0, 60.0);
+        assert_eq!(model.len(), 1);
+        assert_eq!(model.samples[0].percent, 60.0);
+
+        model.record(1000.0, 80.0);
+        assert_eq!(model.len(), 2);
+        // Out of range percentages are clamped
+        model.record(5.0, 140.0);
+        assert_eq!(model.samples[2].percent, 100.0);
+    }
+
+    #[test]
+    fn oldest_samples_are_evicted() {
+        let mut model = LearnedModel::new("unused.toml");
+        let lux: Vec<f32> = (0..MAX_SAMPLES + 8)
+            .map(|i| 10f32.powf(1.0 + i as f32 * 0.2))
+            .collect();
+        for &l in &lux {
+            model.record(l, 50.0);
+        }
+        assert_eq!(model.len(), MAX_SAMPLES);
+        assert_eq!(model.samples[0].lux, lux[8]);
+    }
+
+    #[test]
+    fn corrections_interpolate_and_fade_out() {
+        let curve = curve();
+        let mode = MappingMode::Linear;
+        let mut model = LearnedModel::new("unused.toml");
+        assert_eq!(
+            model.percent_at(&curve, 10.0, mode),
+            curve.percent_at(10.0, mode)
+        );
+
+        model.record(10.0, 50.0);
+        assert!(close(model.percent_at(&curve, 10.0, mode), 50.0));
+
+        // Half the correction is left half a decade out, none a decade out
+        for decades in [-0.5, 0.5] {
+            let lux = decades_from(10.0, decades);
+            let base = curve.percent_at(lux, mode);
+            let expected = base + (50.0 - curve.percent_at(10.0, mode)) / 2.0;
+            assert!(close(model.percent_at(&curve, lux, mode), expected));
+        }
+        let lux = decades_from(10.0, 1.0);
+        assert_eq!(
+            model.percent_at(&curve, lux, mode),
+            curve.percent_at(lux, mode)
+        );
+
+        // Between samples the corrections are interpolated in log-lux space
+        model.record(1000.0, 30.0);
+        let lux = decades_from(10.0, (log_lux(1000.0) - log_lux(10.0)) / 2.0);
+        let correction =
+            ((50.0 - curve.percent_at(10.0, mode)) + (30.0 - curve.percent_at(1000.0, mode))) / 2.0;
+        let expected = curve.percent_at(lux, mode) + correction;
+        assert!(close(model.percent_at(&curve, lux, mode), expected));
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = TempDir::new("learn");
+        let path = dir.join("learned.toml");
+        assert_eq!(LearnedModel::load(&path).unwrap().len(), 0);
+
+        let mut model = LearnedModel::new(&path);
+        model.record(12.5, 40.25);
+        model.record(800.0, 75.5);
+        model.save().unwrap();
+
+        let loaded = LearnedModel::load(&path).unwrap();
+        assert_eq!(loaded.samples, model.samples);
+
+        fs::write(&path, "samples = [[1.0]]\n").unwrap();
+        assert!(LearnedModel::load(&path).is_err());
+    }
+}
@@ -2,42 +2,70 @@ use std::{
     process,
     sync::{
         Arc,
-        atomic::{AtomicBool, Ordering},
+        atomic::AtomicBool,
         mpsc::{self, Receiver, Sender},
     },
     thread,
-    time::Duration,
 };
 
-use slog::{debug, error, info, o, warn};
+use slog::{error, info, o, warn};
 
 mod backlight;
+mod bus;
 mod config;
+mod control;
+mod curve;
 mod device;
 mod error;
+mod events;
+mod filter;
+mod hysteresis;
+mod keyboard;
+mod learn;
 mod logger;
+mod mode;
 mod paths;
+mod power;
+mod profile;
+mod sampler;
+mod sensor;
 mod server;
 mod signal;
+mod solar;
+mod state;
+mod status;
+#[cfg(test)]
+mod testutil;
+mod transition;
 
+use bus::EventBus;
 use config::Config;
-use device::{find_backlight_device, find_illuminance_device, read_max_brightness};
+use control::Controls;
+use device::find_illuminance_device;
 use error::Result;
+use events::{IioEvents, SensingMode};
+use filter::LuxFilter;
+use learn::LearnedModel;
+use mode::Mode;
 use paths::Paths;
-use server::LumdCommand;
+use sampler::{Sampler, idle_sleep_secs, manual_hold, open_keyboard};
+use sensor::Sensor;
+use server::Request;
+use solar::SolarSchedule;
+use state::RuntimeState;
+use status::SampleStatus;
 
 fn main() -> Result<()> {
     // Initialize application paths
     let paths = match Paths::new() {
         Ok(paths) => {
             // Ensure config directory exists
-            if let Some(parent) = paths.config_file().parent() {
-                if !parent.exists() {
-                    if let Err(e) = std::fs::create_dir_all(parent) {
-                        eprintln!("Failed to create config directory: {}", e);
-                        process::exit(1);
-                    }
-                }
+            if let Some(parent) = paths.config_file().parent()
+                && !parent.exists()
+                && let Err(e) = std::fs::create_dir_all(parent)
+            {
+                eprintln!("Failed to create config directory: {}", e);
+                process::exit(1);
             }
             paths
         }
@@ -55,13 +83,12 @@ fn main() -> Result<()> {
     // Set up running flag for clean shutdown
     let running = Arc::new(AtomicBool::new(true));
 
-    // Set up signal handler
-    signal::setup_signal_handler(log.clone(), Arc::clone(&running))?;
-
     // Load configuration
-    let config = match Config::from_file(paths.config_file()) {
+    let file_config = match Config::from_file(paths.config_file()) {
         Ok(config) => {
-            info!(log, "Loaded configuration from file"; "path" => %paths.config_file().display());
+            info!(log, "Loaded configuration from file";
+                "path" => %paths.config_file().display(),
+                "profiles" => ?config.profiles.keys().collect::<Vec<_>>());
             config
         }
         Err(e) => {
@@ -70,11 +97,67 @@ fn main() -> Result<()> {
         }
     };
 
+    // Restore runtime state from the previous run
+    let mut state = match RuntimeState::load(paths.state_file()) {
+        Ok(state) => {
+            info!(log, "Loaded runtime state";
+                "path" => %paths.state_file().display(),
+                "offset" => state.offset,
+                "profile" => &state.profile,
+                "brightness" => ?state.brightness);
+            state
+        }
+        Err(e) => {
+            warn!(log, "Could not load runtime state, starting fresh"; "error" => %e);
+            RuntimeState::new(paths.state_file())
+        }
+    };
+
+    // Layer the active profile over the file's settings
+    let config = match file_config.with_profile(state.profile.as_deref()) {
+        Ok(config) => {
+            if let Some(profile) = &state.profile {
+                info!(log, "Using profile"; "profile" => profile);
+            }
+            config
+        }
+        Err(e) => {
+            warn!(log, "Could not apply saved profile, using base settings"; "error" => %e);
+            state.profile = None;
+            file_config.clone()
+        }
+    };
+
+    // Solar schedule, used without a sensor or to shape the sensor's target
+    let schedule = SolarSchedule::new(&config.schedule);
+    if let Some(schedule) = &schedule {
+        let elevation = schedule.elevation_now();
+        info!(log, "Following solar schedule";
+            "blend" => ?schedule.blend(),
+            "sun_elevation" => format!("{:.1}", elevation),
+            "percent" => format!("{:.1}", schedule.percent_at(elevation)));
+    }
+
     // Find required devices
-    let iio_path = match find_illuminance_device() {
-        Ok(path) => {
-            info!(log, "Found illuminance device"; "path" => %path.display());
-            path
+    let sensor = match find_illuminance_device(&log, &config.sensor) {
+        Ok((path, channel)) => {
+            info!(log, "Found illuminance device";
+                "path" => %path.display(),
+                "channel" => &channel.prefix,
+                "processed" => channel.processed);
+            Some(Sensor::open(
+                &log,
+                path,
+                channel,
+                config.sensor_backend,
+                config.buffer_sampling_hz,
+                config.sensor.trigger.as_deref(),
+            ))
+        }
+        Err(e) if schedule.is_some() => {
+            warn!(log, "No illuminance device, brightness follows the solar schedule";
+                "error" => %e);
+            None
         }
         Err(e) => {
             error!(log, "Failed to find illuminance device"; "error" => %e);
@@ -82,10 +165,15 @@ fn main() -> Result<()> {
         }
     };
 
-    let backlight_path = match find_backlight_device() {
-        Ok(path) => {
-            info!(log, "Found backlight device"; "path" => %path.display());
-            path
+    // Find backlight outputs and read their max brightness values
+    let outputs = match backlight::open_outputs(&log, &config) {
+        Ok(outputs) => {
+            for output in &outputs {
+                info!(log, "Found backlight device";
+                    "path" => %output.path.display(),
+                    "max_brightness" => output.limits.max_brightness);
+            }
+            outputs
         }
         Err(e) => {
             error!(log, "Failed to find backlight device"; "error" => %e);
@@ -93,119 +181,167 @@ fn main() -> Result<()> {
         }
     };
 
-    // Read max brightness value
-    let max_brightness = match read_max_brightness(&backlight_path) {
-        Ok(max) => {
-            info!(log, "Read max brightness"; "value" => max);
-            max
+    let keyboard = open_keyboard(&log, &config);
+
+    // Load the learned preference model
+    let learned = match LearnedModel::load(paths.learned_file()) {
+        Ok(model) => {
+            info!(log, "Loaded learned preferences";
+                "path" => %paths.learned_file().display(),
+                "samples" => model.len());
+            model
         }
         Err(e) => {
-            error!(log, "Failed to read max brightness"; "error" => %e);
-            return Err(e);
+            warn!(log, "Could not load learned preferences, starting fresh"; "error" => %e);
+            LearnedModel::new(paths.learned_file())
         }
     };
 
+    // Keep the brightness from before the first run if lumd did not exit cleanly
+    for output in &outputs {
+        state
+            .original_brightness
+            .entry(output.name.clone())
+            .or_insert_with(|| output.last_written.get());
+    }
+    if let Err(e) = state.save() {
+        error!(log, "Failed to save runtime state"; "error" => %e);
+    }
+
+    // Go back to the brightness of the last run, the first sample carries on from there.
+    // A manual hold keeps it; a paused lumd leaves the backlight alone.
+    let mode = Mode::from_state(state.mode.as_deref(), state.hold_until);
+    if mode != Mode::Paused {
+        for output in &outputs {
+            if let Some(&value) = state.brightness.get(&output.name) {
+                let value =
+                    value.clamp(output.limits.min.min(output.limits.max), output.limits.max);
+                info!(log, "Restoring saved brightness";
+                    "output" => &output.name,
+                    "brightness" => value);
+                output.fader.set_target(value, true);
+            }
+        }
+    }
+
+    // Set up sensor smoothing
+    let filter = LuxFilter::new(config.filter.clone());
+
     // Set up socket path
     let socket_path = paths.socket_path();
 
+    // Set up channel for communication between threads
+    let (tx, rx): (Sender<Request>, Receiver<Request>) = mpsc::channel();
+
+    // Set up signal handler
+    signal::setup_signal_handler(log.clone(), Arc::clone(&running), tx.clone())?;
+
+    // Use sensor threshold events when configured and supported, otherwise poll
+    let events = match (config.sensing_mode, &sensor) {
+        (SensingMode::Poll, _) | (SensingMode::Events, None) => None,
+        (SensingMode::Events, Some(sensor)) => {
+            match IioEvents::open(
+                sensor.path(),
+                sensor.channel(),
+                config.event_window_percent,
+                sensor.device_fd(),
+            )
+            .and_then(|mut ev| {
+                ev.spawn_watcher(
+                    log.new(o!("component" => "light_events")),
+                    tx.clone(),
+                    Arc::clone(&running),
+                )?;
+                Ok(ev)
+            }) {
+                Ok(ev) => {
+                    info!(log, "Using light threshold events";
+                        "window_percent" => config.event_window_percent);
+                    Some(ev)
+                }
+                Err(e) => {
+                    warn!(log, "Light threshold events unavailable, falling back to polling";
+                        "error" => %e);
+                    None
+                }
+            }
+        }
+    };
+    let idle_sleep = idle_sleep_secs(&config, events.is_some());
+
     // Initialize variables
-    let mut sleep = config.sample_interval_secs;
-    let mut offset = config.brightness_offset;
-    let mut instant = true;
+    let controls = Controls::new(
+        state.offset.unwrap_or(config.brightness_offset),
+        mode,
+        config.manual_adjustment_amount,
+        manual_hold(&config),
+    );
 
-    // Set up channel for communication between threads
-    let (tx, rx): (Sender<LumdCommand>, Receiver<LumdCommand>) = mpsc::channel();
+    // Wake up when brightness keys or other tools change the backlight
+    if config.follow_external_changes
+        && let Err(e) = backlight::spawn_brightness_watcher(
+            log.new(o!("component" => "brightness_watcher")),
+            &outputs,
+            tx.clone(),
+            Arc::clone(&running),
+        )
+    {
+        warn!(log, "Cannot watch for external brightness changes, checking each sample";
+            "error" => %e);
+    }
+
+    // Clients subscribed over the socket hear about what the sampler loop does
+    let bus = EventBus::default();
 
     // Spawn socket server
     let tx_clone = tx.clone();
+    let bus_clone = bus.clone();
     let socket_log = log.new(o!("component" => "socket_server"));
     let running_clone = Arc::clone(&running);
     let server_log = log.clone();
     let socket_path_owned = socket_path.to_path_buf();
     thread::spawn(move || {
-        if let Err(e) =
-            server::socket_server(socket_log, socket_path_owned, tx_clone, running_clone)
-        {
+        if let Err(e) = server::socket_server(
+            socket_log,
+            socket_path_owned,
+            tx_clone,
+            bus_clone,
+            running_clone,
+        ) {
             error!(server_log, "Socket server error"; "error" => %e);
         }
     });
 
     // Main sampling loop
-    let sample_log = log.new(o!("component" => "light_sampler"));
-    while running.load(Ordering::SeqCst) {
-        let mut force = false;
-        let mut next_offset = offset;
-        if !instant {
-            // Wait for either a manual trigger or sleep seconds
-            match rx.recv_timeout(Duration::from_secs(sleep)) {
-                Ok(cmd) => match cmd {
-                    LumdCommand::Resample => {
-                        info!(sample_log, "Received early resample signal");
-                        instant = false;
-                        sleep = 30;
-                        force = true;
-                    }
-                    LumdCommand::BrightnessUp => {
-                        next_offset += config.manual_adjustment_amount;
-                        info!(sample_log, "Increasing brightness offset"; 
-                              "new_offset" => next_offset, 
-                              "adjustment" => config.manual_adjustment_amount);
-                        instant = true;
-                        sleep = 30;
-                    }
-                    LumdCommand::BrightnessDown => {
-                        next_offset -= config.manual_adjustment_amount;
-                        info!(sample_log, "Decreasing brightness offset"; 
-                              "new_offset" => next_offset, 
-                              "adjustment" => config.manual_adjustment_amount);
-                        instant = true;
-                        sleep = 30;
-                    }
-                    LumdCommand::Shutdown => {
-                        info!(sample_log, "Received shutdown command");
-                        running.store(false, Ordering::SeqCst);
-                        break;
-                    }
-                },
-                Err(mpsc::RecvTimeoutError::Timeout) => {
-                    // fall through to next iteration (normal sampling)
-                    debug!(
-                        sample_log,
-                        "Sampling timeout reached, proceeding with normal sample"
-                    );
-                    sleep = config.sample_interval_secs;
-                }
-                Err(e) => {
-                    error!(sample_log, "Trigger channel error"; "error" => %e);
-                    break;
-                }
-            }
-        }
-
-        match backlight::read_and_adjust_ambient_light(
-            &sample_log,
-            &iio_path,
-            &backlight_path,
-            max_brightness,
-            &config,
-            next_offset,
-            instant,
-            force,
-        ) {
-            Ok(changed) => {
-                if changed {
-                    offset = next_offset;
-                    debug!(sample_log, "Updated brightness offset"; "offset" => offset);
-                }
-            }
-            Err(e) => {
-                error!(sample_log, "Failed to adjust brightness"; "error" => %e);
-            }
-        }
-
-        instant = false;
-    }
+    let mut sampler = Sampler {
+        log: log.new(o!("component" => "light_sampler")),
+        running,
+        bus,
+        file_config,
+        // Profiles are layered onto the configuration from the file
+        base_config: config.clone(),
+        config,
+        state,
+        learned,
+        controls,
+        outputs,
+        sensor,
+        events,
+        schedule,
+        filter,
+        keyboard,
+        sleep: idle_sleep,
+        idle_sleep,
+        power_profile: None,
+        last_sample: SampleStatus::default(),
+        saved_mode: mode,
+        reload: false,
+        sensor_lost: false,
+        backlight_lost: false,
+    };
+    sampler.run(&rx);
 
     info!(log, "Shutting down lumd gracefully");
+    sampler.shutdown();
     Ok(())
 }
@@ -0,0 +1,132 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// What lumd does with the backlight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    // Follow the ambient light
+    Auto,
+    // Keep the brightness the user chose, until the deadline if there is one
+    Manual { until: Option<SystemTime> },
+    // Leave the backlight alone entirely
+    Paused,
+}
+
+impl Mode {
+    // A manual hold lasting `duration`, or until changed when None
+    pub fn hold(duration: Option<Duration>) -> Self {
+        Mode::Manual {
+            until: duration.map(|d| SystemTime::now() + d),
+        }
+    }
+
+    // Restore a mode saved with `name` and `hold_until_secs`
+    pub fn from_state(name: Option<&str>, until_secs: Option<u64>) -> Self {
+        match name {
+            Some("manual") => Mode::Manual {
+                until: until_secs.map(|s| UNIX_EPOCH + Duration::from_secs(s)),
+            },
+            Some("paused") => Mode::Paused,
+            _ => Mode::Auto,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Auto => "auto",
+            Mode::Manual { .. } => "manual",
+            Mode::Paused => "paused",
+        }
+    }
+
+    // End of a timed hold as seconds since the epoch
+    pub fn hold_until_secs(&self) -> Option<u64> {
+        match self {
+            Mode::Manual { until: Some(until) } => {
+                until.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+            }
+            _ => None,
+        }
+    }
+
+    // Time left on a timed hold
+    pub fn remaining(&self) -> Option<Duration> {
+        match self {
+            Mode::Manual { until: Some(until) } => {
+                Some(until.duration_since(SystemTime::now()).unwrap_or_default())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        self.remaining().is_some_and(|r| r.is_zero())
+    }
+
+    // Mode after the user adjusted brightness by hand, holding for `hold`.
+    // Auto starts a timed hold, a timed hold is extended if needed, and an
+    // open-ended hold or a pause is left alone.
+    pub fn after_manual(self, hold: Option<Duration>) -> Self {
+        match (self, hold) {
+            (Mode::Auto, Some(_)) => Mode::hold(hold),
+            (Mode::Manual { until: Some(until) }, Some(hold)) => Mode::Manual {
+                until: Some(until.max(SystemTime::now() + hold)),
+            },
+            (mode, _) => mode,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOLD: Option<Duration> = Some(Duration::from_secs(60));
+
+    #[test]
+    fn saved_modes_are_restored() {
+        assert_eq!(Mode::from_state(None, None), Mode::Auto);
+        assert_eq!(Mode::from_state(Some("auto"), Some(5)), Mode::Auto);
+        assert_eq!(Mode::from_state(Some("paused"), None), Mode::Paused);
+        assert_eq!(Mode::from_state(Some("bogus"), None), Mode::Auto);
+        assert_eq!(
+            Mode::from_state(Some("manual"), None),
+            Mode::Manual { until: None }
+        );
+
+        let held = Mode::from_state(Some("manual"), Some(1_700_000_000));
+        assert_eq!(held.name(), "manual");
+        assert_eq!(held.hold_until_secs(), Some(1_700_000_000));
+        // A hold that ran out while lumd was stopped is over straight away
+        assert!(held.expired());
+
+        for mode in [Mode::Auto, Mode::Paused, Mode::hold(None), Mode::hold(HOLD)] {
+            let restored = Mode::from_state(Some(mode.name()), mode.hold_until_secs());
+            assert_eq!(restored.name(), mode.name());
+            assert_eq!(restored.hold_until_secs(), mode.hold_until_secs());
+        }
+    }
+
+    #[test]
+    fn manual_adjustment_starts_or_extends_a_hold() {
+        let held = Mode::Auto.after_manual(HOLD);
+        assert!(matches!(held, Mode::Manual { until: Some(_) }));
+        assert!(!held.expired());
+        assert!(held.remaining().unwrap() <= Duration::from_secs(60));
+
+        // A hold about to end is pushed out, a longer one is kept
+        let short = Mode::hold(Some(Duration::from_secs(1))).after_manual(HOLD);
+        assert!(short.remaining().unwrap() > Duration::from_secs(30));
+        let long = Mode::hold(Some(Duration::from_secs(600)));
+        assert_eq!(long.after_manual(HOLD), long);
+
+        // Open-ended holds and pauses are left alone, as is auto without a hold time
+        assert_eq!(
+            Mode::hold(None).after_manual(HOLD),
+            Mode::Manual { until: None }
+        );
+        assert_eq!(Mode::Paused.after_manual(HOLD), Mode::Paused);
+        assert_eq!(Mode::Auto.after_manual(None), Mode::Auto);
+        assert_eq!(Mode::Auto.remaining(), None);
+        assert!(!Mode::Paused.expired());
+    }
+}
@@ -5,6 +5,8 @@ use xdg::BaseDirectories;
 pub struct Paths {
     pub config_file_path: PathBuf,
     pub socket_path: PathBuf,
+    pub learned_file_path: PathBuf,
+    pub state_file_path: PathBuf,
 }
 
 impl Paths {
@@ -35,12 +37,24 @@ impl Paths {
             .place_config_file("config.toml")
             .map_err(|e| LumdError::InvalidData(format!("Could not create config path: {}", e)))?;
 
+        // Create learned model path
+        let learned_file_path = xdg
+            .place_data_file("learned.toml")
+            .map_err(|e| LumdError::InvalidData(format!("Could not create data path: {}", e)))?;
+
+        // Create runtime state path
+        let state_file_path = xdg
+            .place_state_file("state.toml")
+            .map_err(|e| LumdError::InvalidData(format!("Could not create state path: {}", e)))?;
+
         // Create socket path
         let socket_path = runtime_dir.join("lumd.sock");
 
         Ok(Self {
             config_file_path,
             socket_path,
+            learned_file_path,
+            state_file_path,
         })
     }
 
@@ -52,4 +66,12 @@ impl Paths {
     pub fn socket_path(&self) -> &PathBuf {
         &self.socket_path
     }
+
+    pub fn learned_file(&self) -> &PathBuf {
+        &self.learned_file_path
+    }
+
+    pub fn state_file(&self) -> &PathBuf {
+        &self.state_file_path
+    }
 }
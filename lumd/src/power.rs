@@ -0,0 +1,180 @@
+use crate::config::Config;
+use crate::profile::ProfileOverrides;
+use std::fs;
+use std::path::Path;
+
+// Where the machine currently draws power from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerSource {
+    Ac,
+    Battery,
+}
+
+// Power source and remaining system battery
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerState {
+    pub source: PowerSource,
+    // Average charge of the system batteries, in percent
+    pub capacity: Option<f32>,
+}
+
+// Profile picked from the power state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerProfile {
+    Ac,
+    Battery,
+    LowBattery,
+}
+
+impl PowerProfile {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PowerProfile::Ac => "ac",
+            PowerProfile::Battery => "battery",
+            PowerProfile::LowBattery => "low-battery",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PowerConfig {
+    pub ac: ProfileOverrides,
+    pub battery: ProfileOverrides,
+    // Below this battery percentage `low_battery_max_percent` caps brightness
+    pub low_battery_percent: f32,
+    pub low_battery_max_percent: f32,
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self {
+            ac: ProfileOverrides::default(),
+            battery: ProfileOverrides::default(),
+            low_battery_percent: 10.0,
+            low_battery_max_percent: 30.0,
+        }
+    }
+}
+
+impl PowerConfig {
+    pub fn profile(&self, state: &PowerState) -> PowerProfile {
+        match (state.source, state.capacity) {
+            (PowerSource::Ac, _) => PowerProfile::Ac,
+            (PowerSource::Battery, Some(capacity)) if capacity < self.low_battery_percent => {
+                PowerProfile::LowBattery
+            }
+            (PowerSource::Battery, _) => PowerProfile::Battery,
+        }
+    }
+
+    // Layer the overrides for `profile` onto `config`
+    pub fn apply_to(&self, profile: PowerProfile, config: &mut Config) {
+        match profile {
+            PowerProfile::Ac => self.ac.apply_to(config),
+            PowerProfile::Battery => self.battery.apply_to(config),
+            PowerProfile::LowBattery => {
+                self.battery.apply_to(config);
+                config.max_percent = config.max_percent.min(self.low_battery_max_percent);
+            }
+        }
+    }
+}
+
+fn read_attribute(path: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(path.join(name))
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+// Read `/sys/class/power_supply`, returning None on machines without a battery
+pub fn read_power_state() -> Option<PowerState> {
+    let mut online = false;
+    let mut discharging = false;
+    let mut capacities = Vec::new();
+
+    for entry in fs::read_dir("/sys/class/power_supply/").ok()?.flatten() {
+        let path = entry.path();
+        match read_attribute(&path, "type").as_deref() {
+            Some("Mains") | Some("USB") => {
+                online |= read_attribute(&path, "online").as_deref() == Some("1");
+            }
+            // Peripheral batteries (mice, headsets) report scope = Device
+            Some("Battery") if read_attribute(&path, "scope").as_deref() != Some("Device") => {
+                discharging |= read_attribute(&path, "status").as_deref() == Some("Discharging");
+                if let Some(capacity) =
+                    read_attribute(&path, "capacity").and_then(|c| c.parse::<f32>().ok())
+                {
+                    capacities.push(capacity);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if capacities.is_empty() && !discharging {
+        return None;
+    }
+
+    let source = if online && !discharging {
+        PowerSource::Ac
+    } else {
+        PowerSource::Battery
+    };
+    let capacity =
+        (!capacities.is_empty()).then(|| capacities.iter().sum::<f32>() / capacities.len() as f32);
+    Some(PowerState { source, capacity })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn battery(capacity: Option<f32>) -> PowerState {
+        PowerState {
+            source: PowerSource::Battery,
+            capacity,
+        }
+    }
+
+    #[test]
+    fn profile_follows_source_and_capacity() {
+        let power = PowerConfig::default();
+        let ac = PowerState {
+            source: PowerSource::Ac,
+            capacity: Some(5.0),
+        };
+        assert_eq!(power.profile(&ac), PowerProfile::Ac);
+        assert_eq!(power.profile(&battery(Some(50.0))), PowerProfile::Battery);
+        assert_eq!(power.profile(&battery(Some(10.0))), PowerProfile::Battery);
+        assert_eq!(power.profile(&battery(Some(9.0))), PowerProfile::LowBattery);
+        assert_eq!(power.profile(&battery(None)), PowerProfile::Battery);
+    }
+
+    #[test]
+    fn low_battery_caps_on_top_of_battery_overrides() {
+        let power = PowerConfig {
+            battery: ProfileOverrides {
+                brightness_offset: Some(-20),
+                max_percent: Some(80.0),
+                ..ProfileOverrides::default()
+            },
+            ..PowerConfig::default()
+        };
+
+        let mut config = Config::default();
+        power.apply_to(PowerProfile::Battery, &mut config);
+        assert_eq!(config.brightness_offset, -20);
+        assert_eq!(config.max_percent, 80.0);
+
+        let mut config = Config::default();
+        power.apply_to(PowerProfile::LowBattery, &mut config);
+        assert_eq!(config.brightness_offset, -20);
+        assert_eq!(config.max_percent, 30.0);
+
+        let mut config = Config::default();
+        let base_offset = config.brightness_offset;
+        power.apply_to(PowerProfile::Ac, &mut config);
+        assert_eq!(config.brightness_offset, base_offset);
+        assert_eq!(config.max_percent, 100.0);
+    }
+}
@@ -0,0 +1,47 @@
+use crate::config::Config;
+use crate::curve::Curve;
+use serde_json::{Value, json};
+
+// Settings a profile can override on top of the base configuration
+#[derive(Debug, Clone, Default)]
+pub struct ProfileOverrides {
+    pub brightness_offset: Option<i32>,
+    pub curve: Option<Curve>,
+    // Hard cap on brightness, in percent of each output's maximum
+    pub max_percent: Option<f32>,
+}
+
+impl ProfileOverrides {
+    // The overrides that are set, as reported by the `config` request
+    pub fn to_json(&self) -> Value {
+        let mut overrides = json!({});
+        if let Some(offset) = self.brightness_offset {
+            overrides["brightness_offset"] = json!(offset);
+        }
+        if let Some(curve) = &self.curve {
+            let points: Vec<Value> = curve
+                .points()
+                .iter()
+                .map(|p| json!([p.lux, p.percent]))
+                .collect();
+            overrides["curve"] = json!({ "points": points });
+        }
+        if let Some(max_percent) = self.max_percent {
+            overrides["max_percent"] = json!(max_percent);
+        }
+        overrides
+    }
+
+    // Layer these overrides onto `config`. Caps only ever tighten.
+    pub fn apply_to(&self, config: &mut Config) {
+        if let Some(offset) = self.brightness_offset {
+            config.brightness_offset = offset;
+        }
+        if let Some(curve) = &self.curve {
+            config.curve = curve.clone();
+        }
+        if let Some(max_percent) = self.max_percent {
+            config.max_percent = config.max_percent.min(max_percent);
+        }
+    }
+}
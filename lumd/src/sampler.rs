@@ -0,0 +1,584 @@
+use crate::backlight::{self, Adjustment, Output};
+use crate::bus::{EventBus, EventKind};
+use crate::config::Config;
+use crate::control::Controls;
+use crate::device::{panel_powered, set_brightness};
+use crate::error::{LumdError, Result};
+use crate::events::IioEvents;
+use crate::filter::LuxFilter;
+use crate::keyboard::KeyboardBacklight;
+use crate::learn::LearnedModel;
+use crate::mode::Mode;
+use crate::power::{self, PowerProfile};
+use crate::sensor::Sensor;
+use crate::server::{CommandError, ErrorCode, LumdCommand, Reply, Request};
+use crate::solar::SolarSchedule;
+use crate::state::RuntimeState;
+use crate::status::{self, SampleStatus, Status};
+use serde_json::json;
+use slog::{Logger, debug, error, info, warn};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+    },
+    time::Duration,
+};
+
+// Everything the sampler loop reads and updates between samples
+pub struct Sampler {
+    pub log: Logger,
+    pub running: Arc<AtomicBool>,
+    pub bus: EventBus,
+    // Settings from the file, profiles are layered onto these
+    pub file_config: Config,
+    // Settings of the named profile, before the power profile is applied
+    pub base_config: Config,
+    pub config: Config,
+    pub state: RuntimeState,
+    pub learned: LearnedModel,
+    pub controls: Controls,
+    pub outputs: Vec<Output>,
+    pub sensor: Option<Sensor>,
+    pub events: Option<IioEvents>,
+    pub schedule: Option<SolarSchedule>,
+    pub filter: LuxFilter,
+    pub keyboard: Option<KeyboardBacklight>,
+    // Seconds until the next sample, and until one when nothing is going on
+    pub sleep: u64,
+    pub idle_sleep: u64,
+    pub power_profile: Option<PowerProfile>,
+    pub last_sample: SampleStatus,
+    // Mode last written to the state file
+    pub saved_mode: Mode,
+    // A profile switch waits for the panel to be on like everything else
+    pub reload: bool,
+    // Device loss is reported once, not on every failed sample
+    pub sensor_lost: bool,
+    pub backlight_lost: bool,
+}
+
+impl Sampler {
+    // Sample until lumd is told to stop
+    pub fn run(&mut self, rx: &Receiver<Request>) {
+        while self.running.load(Ordering::SeqCst) {
+            self.controls.begin_sample();
+            if !self.controls.instant && !self.wait(rx) {
+                break;
+            }
+
+            if self.controls.mode.expired() {
+                info!(
+                    self.log,
+                    "Manual hold expired, following ambient light again"
+                );
+                self.controls.mode = Mode::Auto;
+                self.controls.force = true;
+            }
+
+            let Some(resumed) = self.check_panel() else {
+                continue;
+            };
+            self.apply_power_profile();
+            let external = self.adopt_external(resumed);
+
+            // Paused leaves the backlight alone, a manual hold only follows the user
+            match self.update_mode() {
+                Mode::Paused => {
+                    debug!(self.log, "Paused, not adjusting brightness");
+                    self.controls.instant = false;
+                    continue;
+                }
+                Mode::Manual { .. } if !self.controls.manual => {
+                    debug!(self.log, "Holding manual brightness");
+                    self.controls.instant = false;
+                    continue;
+                }
+                _ => {}
+            }
+
+            self.sample(external);
+            self.controls.instant = false;
+        }
+    }
+
+    // Wait for a command, the next sample or the end of a manual hold.
+    // Returns false when the loop should stop.
+    fn wait(&mut self, rx: &Receiver<Request>) -> bool {
+        let wait = Duration::from_secs(self.sleep)
+            .min(self.controls.mode.remaining().unwrap_or(Duration::MAX));
+        match rx.recv_timeout(wait) {
+            Ok(Request { command, reply }) => {
+                let outcome = self.handle_request(command);
+                Request::respond(reply, outcome);
+                self.running.load(Ordering::SeqCst)
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                debug!(
+                    self.log,
+                    "Sampling timeout reached, proceeding with normal sample"
+                );
+                self.sleep = self.idle_sleep;
+                true
+            }
+            Err(e) => {
+                error!(self.log, "Trigger channel error"; "error" => %e);
+                false
+            }
+        }
+    }
+
+    fn handle_request(&mut self, command: LumdCommand) -> Reply {
+        let handled = self.controls.handle(
+            &self.log,
+            &command,
+            &self.outputs[0].limits,
+            self.outputs[0].fader.target(),
+        );
+        if let Some(outcome) = handled {
+            return outcome;
+        }
+        match command {
+            LumdCommand::SetProfile(name) => self.set_profile(name),
+            LumdCommand::Learned => Ok(self.learned.to_json(&self.outputs, &self.config)),
+            LumdCommand::ResetLearned => self.reset_learned(),
+            LumdCommand::Status => Ok(Status {
+                sample: self.last_sample,
+                outputs: &self.outputs,
+                sensor: self.sensor.as_ref().map(|s| s.path()),
+                offset: self.controls.offset,
+                mode: self.controls.mode,
+                profile: self.state.profile.as_deref(),
+                power_profile: self.power_profile,
+            }
+            .to_json()),
+            LumdCommand::Config => Ok(json!({
+                "profile": self.state.profile,
+                "power_profile": self.power_profile.map(|p| p.name()),
+                "profiles": self.file_config.profiles.keys().collect::<Vec<_>>(),
+                "config": self.config.to_json(),
+            })),
+            LumdCommand::Shutdown => {
+                info!(self.log, "Received shutdown command");
+                self.running.store(false, Ordering::SeqCst);
+                Ok(json!({}))
+            }
+            _ => Ok(json!({})),
+        }
+    }
+
+    // Switch to a named profile, applied at the next sample
+    fn set_profile(&mut self, name: Option<String>) -> Reply {
+        let profile_config =
+            match profile_config(&self.file_config, name.as_deref(), self.sensor.is_some()) {
+                Ok(profile_config) => profile_config,
+                Err(e) => {
+                    warn!(self.log, "Cannot switch profile"; "error" => %e);
+                    return Err(CommandError::new(ErrorCode::Failed, e.to_string()));
+                }
+            };
+        info!(self.log, "Switching profile";
+              "from" => &self.state.profile,
+              "to" => &name);
+        self.base_config = profile_config;
+        self.reload = true;
+        self.state.profile = name;
+        self.save_state();
+        self.bus.publish(
+            EventKind::Profile,
+            json!({
+                "profile": self.state.profile,
+                "power_profile": self.power_profile.map(|p| p.name()),
+            }),
+        );
+        Ok(json!({ "profile": self.state.profile }))
+    }
+
+    fn reset_learned(&mut self) -> Reply {
+        info!(self.log, "Resetting learned preferences";
+              "samples" => self.learned.len());
+        self.learned.clear();
+        self.controls.force = true;
+        self.learned.save().map(|()| json!({})).map_err(|e| {
+            error!(self.log, "Failed to save learned preferences"; "error" => %e);
+            CommandError::new(ErrorCode::Failed, e.to_string())
+        })
+    }
+
+    // Some drivers wake a blanked panel on any brightness write, so wait it out.
+    // Returns None while the panel is off, otherwise whether it just came back on.
+    fn check_panel(&mut self) -> Option<bool> {
+        if !panel_powered(&self.outputs[0].path) {
+            if !self.controls.panel_off {
+                info!(self.log, "Panel is off, pausing adjustments");
+                self.controls.panel_off = true;
+            }
+            self.controls.instant = false;
+            self.sleep = self.config.sample_interval_secs;
+            return None;
+        }
+        let resumed = self.controls.panel_off;
+        if resumed {
+            info!(self.log, "Panel is back on, resampling");
+            // Drivers may restore any brightness on unblank, so the value lumd last
+            // wrote says nothing about what the panel shows now
+            backlight::resync_outputs(&self.log, &self.outputs);
+            self.controls.panel_off = false;
+            self.controls.instant = true;
+            self.controls.force = true;
+        }
+        Some(resumed)
+    }
+
+    // Follow the power source without a restart, and apply a pending profile switch
+    fn apply_power_profile(&mut self) {
+        let power_state = power::read_power_state();
+        let profile = power_state.map(|s| self.base_config.power.profile(&s));
+        if profile == self.power_profile && !self.reload {
+            return;
+        }
+
+        let mut next = self.base_config.clone();
+        if let Some(profile) = profile {
+            self.base_config.power.apply_to(profile, &mut next);
+        }
+        info!(self.log, "Switching power profile";
+            "from" => ?self.power_profile,
+            "to" => ?profile,
+            "capacity" => power_state.and_then(|s| s.capacity),
+            "max_percent" => next.max_percent);
+        // Keep the user's corrections when the profile moves the base offset
+        self.controls
+            .shift_offset(next.brightness_offset - self.config.brightness_offset);
+        self.config = next;
+        // A named profile may change what was built from the settings at startup
+        if self.reload {
+            self.reconfigure();
+        }
+        if profile != self.power_profile {
+            self.bus.publish(
+                EventKind::Profile,
+                json!({
+                    "profile": self.state.profile,
+                    "power_profile": profile.map(|p| p.name()),
+                }),
+            );
+        }
+        self.power_profile = profile;
+        self.bus.publish(
+            EventKind::ConfigReload,
+            json!({
+                "profile": self.state.profile,
+                "power_profile": self.power_profile.map(|p| p.name()),
+                "brightness_offset": self.config.brightness_offset,
+                "max_percent": self.config.max_percent,
+            }),
+        );
+        self.controls.force = true;
+    }
+
+    // Rebuild what was set up from the settings at startup
+    fn reconfigure(&mut self) {
+        self.schedule = SolarSchedule::new(&self.config.schedule);
+        self.filter = LuxFilter::new(self.config.filter.clone());
+        self.keyboard = open_keyboard(&self.log, &self.config);
+        backlight::reconfigure_outputs(&self.log, &mut self.outputs, &self.config);
+        self.controls.configure(
+            self.config.manual_adjustment_amount,
+            manual_hold(&self.config),
+        );
+        self.idle_sleep = idle_sleep_secs(&self.config, self.events.is_some());
+        self.sleep = self.idle_sleep;
+        self.reload = false;
+    }
+
+    // Treat a brightness someone else set like a manual adjustment. Drivers may
+    // report anything after unblanking, so that is not taken as user intent.
+    // Returns whether a change was adopted.
+    fn adopt_external(&mut self, resumed: bool) -> bool {
+        // Changes made while paused were left alone on purpose, so carry on from them
+        // instead of adopting them as an offset
+        if self.saved_mode == Mode::Paused && self.controls.mode != Mode::Paused {
+            backlight::resync_outputs(&self.log, &self.outputs);
+            return false;
+        }
+        if !self.config.follow_external_changes || resumed || self.controls.mode == Mode::Paused {
+            return false;
+        }
+
+        match backlight::external_change(&self.log, &mut self.outputs) {
+            Ok(Some(delta)) => {
+                self.controls.next_offset += delta;
+                info!(self.log, "Adopting external brightness change";
+                    "new_offset" => self.controls.next_offset,
+                    "adjustment" => delta);
+                self.controls.instant = true;
+                self.controls.manual = true;
+                true
+            }
+            Ok(None) => false,
+            Err(e) => {
+                warn!(self.log, "Failed to check for external brightness changes";
+                    "error" => %e);
+                false
+            }
+        }
+    }
+
+    // Start or extend a manual hold and save and announce a new mode
+    fn update_mode(&mut self) -> Mode {
+        if self.controls.manual {
+            self.controls.after_manual();
+        }
+        let mode = self.controls.mode;
+        if mode != self.saved_mode {
+            info!(self.log, "Mode changed";
+                "from" => self.saved_mode.name(),
+                "to" => mode.name(),
+                "hold_secs" => mode.remaining().map(|r| r.as_secs()));
+            self.state.set_mode(mode);
+            self.save_state();
+            self.saved_mode = mode;
+            self.bus.publish(
+                EventKind::Mode,
+                json!({ "mode": mode.name(), "hold_until": mode.hold_until_secs() }),
+            );
+        }
+        mode
+    }
+
+    // Read the light and move the outputs to match. `external` is set when the
+    // sample carries a brightness adopted from outside lumd.
+    fn sample(&mut self, external: bool) {
+        let adjustment = match backlight::read_and_adjust_ambient_light(
+            &self.log,
+            self.sensor.as_mut(),
+            self.schedule.as_ref(),
+            &mut self.outputs,
+            &self.config,
+            &self.learned,
+            &mut self.filter,
+            self.controls.next_offset,
+            self.controls.instant,
+            self.controls.force,
+        ) {
+            Ok(adjustment) => adjustment,
+            Err(e) => {
+                error!(self.log, "Failed to adjust brightness"; "error" => %e);
+                if !self.backlight_lost {
+                    self.backlight_lost = true;
+                    self.bus.publish(
+                        EventKind::DeviceLost,
+                        json!({ "device": "backlight", "error": e.to_string() }),
+                    );
+                }
+                return;
+            }
+        };
+        self.backlight_lost = false;
+        self.report_sample(&adjustment);
+
+        // Re-arm the threshold window around the light level just seen. The
+        // kernel compares unfiltered readings, so the window must be around those.
+        if let (Some(ev), Some(raw_lux)) = (&self.events, adjustment.raw_lux)
+            && let Err(e) = ev.set_window(raw_lux)
+        {
+            warn!(self.log, "Failed to program light thresholds"; "error" => %e);
+        }
+
+        // No event fires for a change already outside the window, so keep
+        // sampling while one is held back by the spike filter or dwell time
+        let holding =
+            self.filter.is_holding() || self.outputs.iter().any(|o| o.hysteresis.is_pending());
+        self.sleep = if holding {
+            self.config.sample_interval_secs
+        } else {
+            self.idle_sleep
+        };
+
+        if let (Some(kbd), Some(lux)) = (&mut self.keyboard, adjustment.lux)
+            && let Err(e) = kbd.update(&self.log, lux)
+        {
+            warn!(self.log, "Failed to set keyboard backlight"; "error" => %e);
+        }
+
+        // An adopted external change usually needs no write, keep it anyway
+        if !adjustment.changed && !external {
+            return;
+        }
+        self.controls.offset = self.controls.next_offset;
+        debug!(self.log, "Updated brightness offset"; "offset" => self.controls.offset);
+        if self.controls.manual {
+            self.learn(&adjustment);
+        }
+        self.publish_brightness();
+    }
+
+    // Record the sample for status and tell subscribers about the light or a lost sensor
+    fn report_sample(&mut self, adjustment: &Adjustment) {
+        // A sensor without a new sample has nothing to report
+        if let Some(time) = adjustment.time {
+            self.last_sample
+                .record(adjustment.raw_lux, adjustment.lux, time);
+        }
+        match (&self.sensor, adjustment.lux, adjustment.time) {
+            (Some(_), Some(lux), Some(_)) => {
+                self.sensor_lost = false;
+                self.bus.publish(
+                    EventKind::Lux,
+                    json!({ "lux": adjustment.raw_lux, "filtered_lux": lux }),
+                );
+            }
+            // With a sensor, a sample without lux means it could not be read
+            (Some(sensor), None, Some(_)) if !self.sensor_lost => {
+                self.sensor_lost = true;
+                self.bus.publish(
+                    EventKind::DeviceLost,
+                    json!({
+                        "device": "sensor",
+                        "path": sensor.path().display().to_string(),
+                    }),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    // Fold a manual correction into the learned curve instead of the global offset.
+    // At a limit the wanted percentage is unknown, so that stays in the offset.
+    fn learn(&mut self, adjustment: &Adjustment) {
+        if !self.config.learn_preferences {
+            return;
+        }
+        let Some(lux) = adjustment.lux else {
+            return;
+        };
+        let Some(percent) = self.outputs[0].limits.percent(
+            adjustment.target,
+            self.config.mapping_mode,
+            self.config.brightness_offset,
+            self.config.max_percent,
+        ) else {
+            return;
+        };
+
+        self.learned.record(lux, percent);
+        self.controls.offset = self.config.brightness_offset;
+        info!(self.log, "Learned brightness preference";
+            "lux" => format!("{:.1}", lux),
+            "percent" => format!("{:.1}", percent),
+            "samples" => self.learned.len());
+        if let Err(e) = self.learned.save() {
+            error!(self.log, "Failed to save learned preferences"; "error" => %e);
+        }
+    }
+
+    // Tell subscribers where the outputs are heading and remember the new offset
+    fn publish_brightness(&mut self) {
+        let targets: Vec<_> = self
+            .outputs
+            .iter()
+            .map(|o| {
+                json!({
+                    "name": o.name,
+                    "target": o.fader.target(),
+                    "target_percent": status::percent(
+                        o.fader.target(),
+                        o.limits.max_brightness,
+                    ),
+                })
+            })
+            .collect();
+        self.bus.publish(
+            EventKind::Brightness,
+            json!({
+                "offset": self.controls.offset,
+                "manual": self.controls.manual,
+                "outputs": targets,
+            }),
+        );
+
+        // The brightness alone is written out at shutdown, not on every change
+        self.state.record_outputs(&self.outputs);
+        if self.state.offset != Some(self.controls.offset) {
+            self.state.offset = Some(self.controls.offset);
+            self.save_state();
+        }
+    }
+
+    fn save_state(&self) {
+        if let Err(e) = self.state.save() {
+            error!(self.log, "Failed to save runtime state"; "error" => %e);
+        }
+    }
+
+    // Stop writing, put the original brightness back if configured and save the state
+    pub fn shutdown(&mut self) {
+        // Stop any running fade so nothing writes after this point
+        for output in &mut self.outputs {
+            output.fader.stop();
+        }
+        self.state.record_outputs(&self.outputs);
+
+        if self.config.restore_brightness_on_exit {
+            for output in &self.outputs {
+                if let Some(&value) = self.state.original_brightness.get(&output.name) {
+                    info!(self.log, "Restoring original brightness";
+                        "output" => &output.name,
+                        "brightness" => value);
+                    if let Err(e) = set_brightness(&output.path, value) {
+                        error!(self.log, "Failed to restore brightness"; "error" => %e);
+                    }
+                }
+            }
+        }
+
+        // A clean exit lets the next run capture a fresh original brightness
+        self.state.original_brightness.clear();
+        self.save_state();
+    }
+}
+
+// Settings for a named profile, if lumd can run on them
+pub fn profile_config(
+    file_config: &Config,
+    name: Option<&str>,
+    has_sensor: bool,
+) -> Result<Config> {
+    let config = file_config.with_profile(name)?;
+    // Without a sensor there is nothing but the schedule to follow
+    if !has_sensor && SolarSchedule::new(&config.schedule).is_none() {
+        return Err(LumdError::InvalidData(
+            "lumd has no light sensor and the profile has no solar schedule".into(),
+        ));
+    }
+    Ok(config)
+}
+
+// Keyboard backlight is optional, lumd keeps running without it
+pub fn open_keyboard(log: &Logger, config: &Config) -> Option<KeyboardBacklight> {
+    if !config.keyboard.enabled {
+        return None;
+    }
+    match KeyboardBacklight::open(log, &config.keyboard) {
+        Ok(keyboard) => Some(keyboard),
+        Err(e) => {
+            warn!(log, "Keyboard backlight unavailable"; "error" => %e);
+            None
+        }
+    }
+}
+
+// Longest wait between samples when nothing else wakes the sampler loop
+pub fn idle_sleep_secs(config: &Config, events: bool) -> u64 {
+    if events {
+        config.event_max_sleep_secs
+    } else {
+        config.sample_interval_secs
+    }
+}
+
+pub fn manual_hold(config: &Config) -> Option<Duration> {
+    (config.manual_hold_secs > 0).then(|| Duration::from_secs(config.manual_hold_secs))
+}
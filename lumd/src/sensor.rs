@@ -0,0 +1,610 @@
+use crate::device::LightChannel;
+use crate::error::{LumdError, Result};
+use nix::sys::time::TimeValLike;
+use nix::time::{ClockId, clock_gettime};
+use slog::{Logger, info, warn};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read},
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+// Number of scans the kernel buffer can hold
+const BUFFER_LENGTH: usize = 64;
+
+// Where lux samples come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SensorBackend {
+    // Read the channel value from sysfs for every sample
+    #[default]
+    Sysfs,
+    // Stream samples from the IIO buffer on /dev/iio:deviceN
+    Buffer,
+}
+
+impl SensorBackend {
+    pub fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "sysfs" | "poll" => Ok(SensorBackend::Sysfs),
+            "buffer" | "buffered" => Ok(SensorBackend::Buffer),
+            _ => Err(LumdError::InvalidData(format!(
+                "Unknown sensor backend: {} (expected sysfs or buffer)",
+                s
+            ))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SensorBackend::Sysfs => "sysfs",
+            SensorBackend::Buffer => "buffer",
+        }
+    }
+}
+
+// Light sensor selection and conversion settings
+#[derive(Debug, Clone)]
+pub struct SensorConfig {
+    // Pin the sysfs device directory, e.g. "iio:device1" (glob allowed)
+    pub device: Option<String>,
+    // Pin the IIO `name` attribute, e.g. "als" (glob allowed)
+    pub name: Option<String>,
+    // Lux per scaled count of `in_intensity_clear`
+    pub clear_coefficient: f32,
+    // Lux per scaled count of `in_intensity_both`
+    pub both_coefficient: f32,
+    // IIO trigger the buffer may attach when the sensor has none of its own
+    pub trigger: Option<String>,
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        Self {
+            device: None,
+            name: None,
+            clear_coefficient: 1.0,
+            both_coefficient: 1.0,
+            trigger: None,
+        }
+    }
+}
+
+// One light reading and when the sensor took it
+#[derive(Debug, Clone, Copy)]
+pub struct LuxSample {
+    pub lux: f32,
+    pub time: SystemTime,
+}
+
+// The ambient light sensor, read through sysfs or the IIO buffer
+pub struct Sensor {
+    path: PathBuf,
+    channel: LightChannel,
+    buffer: Option<IioBuffer>,
+    log: Logger,
+}
+
+impl Sensor {
+    // Open the sensor, falling back to sysfs if the buffer cannot be set up
+    pub fn open(
+        log: &Logger,
+        path: PathBuf,
+        channel: LightChannel,
+        backend: SensorBackend,
+        sampling_hz: Option<f32>,
+        trigger: Option<&str>,
+    ) -> Self {
+        let buffer = match backend {
+            SensorBackend::Sysfs => None,
+            SensorBackend::Buffer => {
+                match IioBuffer::open(log, &path, &channel, sampling_hz, trigger) {
+                    Ok(buffer) => {
+                        info!(log, "Using IIO buffered capture";
+                            "device" => %buffer.dev_path.display());
+                        Some(buffer)
+                    }
+                    Err(e) => {
+                        warn!(log, "IIO buffered capture unavailable, falling back to sysfs";
+                            "error" => %e);
+                        None
+                    }
+                }
+            }
+        };
+
+        Self {
+            path,
+            channel,
+            buffer,
+            log: log.clone(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn channel(&self) -> &LightChannel {
+        &self.channel
+    }
+
+    // The character device held open by the buffer backend. The kernel lets only one
+    // open file on it, so anything else needing the device has to go through this one.
+    pub fn device_fd(&self) -> Option<BorrowedFd<'_>> {
+        self.buffer.as_ref().map(|buffer| buffer.file.as_fd())
+    }
+
+    // Read the newest lux, None when the buffer captured nothing since the last read.
+    // Switches to sysfs for good if the buffer fails.
+    pub fn read_lux(&mut self) -> Result<Option<LuxSample>> {
+        if let Some(buffer) = &mut self.buffer {
+            match buffer.read_lux() {
+                Ok(sample) => return Ok(sample),
+                Err(e) => {
+                    warn!(self.log, "IIO buffered capture failed, falling back to sysfs";
+                        "error" => %e);
+                    // Dropping the buffer disables it so sysfs reads work again
+                    self.buffer = None;
+                }
+            }
+        }
+        let lux = self.channel.read_lux(&self.path)?;
+        Ok(Some(LuxSample {
+            lux,
+            time: SystemTime::now(),
+        }))
+    }
+}
+
+// Parsed `scan_elements/*_type`, e.g. "le:u16/16>>0"
+#[derive(Debug, Clone, Copy)]
+struct ScanType {
+    big_endian: bool,
+    signed: bool,
+    bits: u32,
+    storage_bytes: usize,
+    shift: u32,
+}
+
+impl ScanType {
+    fn parse(s: &str) -> Result<Self> {
+        let invalid = || LumdError::InvalidData(format!("Unsupported IIO scan type: {}", s));
+        let s = s.trim();
+        let (endian, rest) = s.split_once(':').ok_or_else(invalid)?;
+        let signed = match rest.chars().next() {
+            Some('s') => true,
+            Some('u') => false,
+            _ => return Err(invalid()),
+        };
+        let (bits, rest) = rest[1..].split_once('/').ok_or_else(invalid)?;
+        let (storage, shift) = rest.split_once(">>").ok_or_else(invalid)?;
+        // Repeated channels ("16X2") are not used by light sensors
+        if storage.contains('X') {
+            return Err(invalid());
+        }
+
+        let storage_bits: u32 = storage.parse().map_err(|_| invalid())?;
+        let bits: u32 = bits.parse().map_err(|_| invalid())?;
+        let shift: u32 = shift.parse().map_err(|_| invalid())?;
+        // decode() relies on whole bytes, at most 64 bits, and the value fitting its storage
+        if storage_bits == 0
+            || storage_bits > 64
+            || !storage_bits.is_multiple_of(8)
+            || bits == 0
+            || bits + shift > storage_bits
+        {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            big_endian: endian == "be",
+            signed,
+            bits,
+            storage_bytes: storage_bits as usize / 8,
+            shift,
+        })
+    }
+
+    fn decode(&self, bytes: &[u8]) -> i64 {
+        let mut raw: u64 = 0;
+        for i in 0..self.storage_bytes {
+            let byte = if self.big_endian {
+                bytes[i]
+            } else {
+                bytes[self.storage_bytes - 1 - i]
+            };
+            raw = (raw << 8) | byte as u64;
+        }
+        raw >>= self.shift;
+        let mask = if self.bits >= 64 {
+            u64::MAX
+        } else {
+            (1u64 << self.bits) - 1
+        };
+        raw &= mask;
+        if self.signed && self.bits < 64 && raw & (1u64 << (self.bits - 1)) != 0 {
+            (raw | !mask) as i64
+        } else {
+            raw as i64
+        }
+    }
+}
+
+// Byte offset of each enabled scan element, in scan index order, and the scan size.
+// Every element is aligned to its own storage size, so a 64-bit timestamp sits on 8
+// bytes, and the scan is padded to a multiple of its largest element.
+fn scan_layout(
+    mut elements: Vec<(i32, String, ScanType)>,
+) -> (Vec<(String, usize, ScanType)>, usize) {
+    elements.sort_by_key(|(index, _, _)| *index);
+    let mut layout = Vec::with_capacity(elements.len());
+    let mut position: usize = 0;
+    let mut largest: usize = 1;
+    for (_, name, scan_type) in elements {
+        let size = scan_type.storage_bytes;
+        position = position.div_ceil(size) * size;
+        layout.push((name, position, scan_type));
+        position += size;
+        largest = largest.max(size);
+    }
+    (layout, position.div_ceil(largest) * largest)
+}
+
+// Clock the kernel stamps scans with, None for clocks lumd cannot read
+fn timestamp_clock(iio_path: &Path) -> Option<ClockId> {
+    // Kernels without the attribute always use the realtime clock
+    let name = fs::read_to_string(iio_path.join("current_timestamp_clock"))
+        .unwrap_or_else(|_| "realtime".into());
+    match name.trim() {
+        "realtime" => Some(ClockId::CLOCK_REALTIME),
+        "realtime_coarse" => Some(ClockId::CLOCK_REALTIME_COARSE),
+        "monotonic" => Some(ClockId::CLOCK_MONOTONIC),
+        "monotonic_coarse" => Some(ClockId::CLOCK_MONOTONIC_COARSE),
+        "monotonic_raw" => Some(ClockId::CLOCK_MONOTONIC_RAW),
+        "boottime" => Some(ClockId::CLOCK_BOOTTIME),
+        "tai" => Some(ClockId::CLOCK_TAI),
+        _ => None,
+    }
+}
+
+// Position of the timestamp in a scan and the clock it was taken on
+#[derive(Debug, Clone, Copy)]
+struct Timestamp {
+    offset: usize,
+    scan_type: ScanType,
+    clock: ClockId,
+}
+
+impl Timestamp {
+    // Wall clock time of a scan, from how long ago its timestamp was taken
+    fn time(&self, scan: &[u8]) -> SystemTime {
+        let now = SystemTime::now();
+        let stamp = self.scan_type.decode(&scan[self.offset..]);
+        let Ok(clock_now) = clock_gettime(self.clock) else {
+            return now;
+        };
+        let age = clock_now.num_nanoseconds().saturating_sub(stamp).max(0);
+        now.checked_sub(Duration::from_nanos(age as u64))
+            .unwrap_or(now)
+    }
+}
+
+struct IioBuffer {
+    iio_path: PathBuf,
+    dev_path: PathBuf,
+    file: File,
+    channel: LightChannel,
+    scan_size: usize,
+    lux_offset: usize,
+    lux_type: ScanType,
+    timestamp: Option<Timestamp>,
+    // Scan elements enabled by lumd, switched off again when it is done
+    enabled: Vec<PathBuf>,
+}
+
+impl IioBuffer {
+    fn open(
+        log: &Logger,
+        iio_path: &Path,
+        channel: &LightChannel,
+        sampling_hz: Option<f32>,
+        trigger: Option<&str>,
+    ) -> Result<Self> {
+        let scan_dir = iio_path.join("scan_elements");
+        let channel_en = scan_dir.join(format!("{}_en", channel.prefix));
+        if channel.processed || !channel_en.exists() {
+            return Err(LumdError::DeviceNotFound(format!(
+                "Sensor has no buffered {} channel",
+                channel.prefix
+            )));
+        }
+
+        // Buffer configuration can only change while it is disabled
+        let enable_path = iio_path.join("buffer").join("enable");
+        fs::write(&enable_path, "0")?;
+
+        let mut enabled = Vec::new();
+        match Self::setup(
+            log,
+            iio_path,
+            channel,
+            sampling_hz,
+            trigger,
+            &channel_en,
+            &mut enabled,
+        ) {
+            Ok(buffer) => Ok(buffer),
+            Err(e) => {
+                // Leave the device as lumd found it
+                let _ = fs::write(&enable_path, "0");
+                disable_elements(&enabled);
+                Err(e)
+            }
+        }
+    }
+
+    // Configure and enable the buffer, recording each scan element turned on in `enabled`
+    fn setup(
+        log: &Logger,
+        iio_path: &Path,
+        channel: &LightChannel,
+        sampling_hz: Option<f32>,
+        trigger: Option<&str>,
+        channel_en: &Path,
+        enabled: &mut Vec<PathBuf>,
+    ) -> Result<Self> {
+        let scan_dir = iio_path.join("scan_elements");
+        // Without a clock to relate it to, a timestamp is of no use
+        let clock = timestamp_clock(iio_path);
+        let timestamp_en = scan_dir.join("in_timestamp_en");
+        let mut wanted = vec![channel_en.to_path_buf()];
+        if clock.is_some() && timestamp_en.exists() {
+            wanted.push(timestamp_en);
+        }
+        for element_en in wanted {
+            if fs::read_to_string(&element_en)?.trim() != "1" {
+                fs::write(&element_en, "1")?;
+                enabled.push(element_en);
+            }
+        }
+
+        if let Some(hz) = sampling_hz {
+            for name in [
+                format!("{}_sampling_frequency", channel.prefix),
+                "sampling_frequency".to_string(),
+            ] {
+                let path = iio_path.join(name);
+                if path.exists() {
+                    fs::write(path, hz.to_string())?;
+                    break;
+                }
+            }
+        }
+
+        setup_trigger(log, iio_path, trigger)?;
+
+        let length_path = iio_path.join("buffer").join("length");
+        if length_path.exists() {
+            fs::write(length_path, BUFFER_LENGTH.to_string())?;
+        }
+
+        let mut elements = Vec::new();
+        for entry in fs::read_dir(&scan_dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            let Some(element) = name.strip_suffix("_en") else {
+                continue;
+            };
+            if fs::read_to_string(scan_dir.join(&name))?.trim() != "1" {
+                continue;
+            }
+            let index: i32 = fs::read_to_string(scan_dir.join(format!("{}_index", element)))?
+                .trim()
+                .parse()?;
+            let scan_type = ScanType::parse(&fs::read_to_string(
+                scan_dir.join(format!("{}_type", element)),
+            )?)?;
+            elements.push((index, element.to_string(), scan_type));
+        }
+
+        let (layout, scan_size) = scan_layout(elements);
+        let find = |wanted: &str| {
+            layout
+                .iter()
+                .find(|(name, _, _)| name == wanted)
+                .map(|&(_, offset, scan_type)| (offset, scan_type))
+        };
+        let (lux_offset, lux_type) = find(&channel.prefix)
+            .ok_or_else(|| LumdError::DeviceNotFound("Light channel missing from scan".into()))?;
+        let timestamp = clock
+            .zip(find("in_timestamp"))
+            .map(|(clock, (offset, scan_type))| Timestamp {
+                offset,
+                scan_type,
+                clock,
+            });
+
+        let device_name = iio_path
+            .file_name()
+            .ok_or_else(|| LumdError::DeviceNotFound("Invalid IIO device path".into()))?;
+        let dev_path = Path::new("/dev").join(device_name);
+        fs::write(iio_path.join("buffer").join("enable"), "1")?;
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(nix::libc::O_NONBLOCK)
+            .open(&dev_path)?;
+
+        Ok(Self {
+            iio_path: iio_path.to_path_buf(),
+            dev_path,
+            file,
+            channel: channel.clone(),
+            scan_size,
+            lux_offset,
+            lux_type,
+            timestamp,
+            enabled: std::mem::take(enabled),
+        })
+    }
+
+    // Drain everything queued in the buffer without blocking and keep the newest scan,
+    // None when nothing was captured since the last read
+    fn read_lux(&mut self) -> Result<Option<LuxSample>> {
+        let mut buf = vec![0u8; self.scan_size * BUFFER_LENGTH];
+        let mut latest = None;
+        loop {
+            match self.file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) if n < self.scan_size => break,
+                Ok(n) => {
+                    let start = (n / self.scan_size - 1) * self.scan_size;
+                    let scan = &buf[start..start + self.scan_size];
+                    let raw = self.lux_type.decode(&scan[self.lux_offset..]);
+                    latest = Some(LuxSample {
+                        lux: self.channel.value_to_lux(raw as f32),
+                        time: self
+                            .timestamp
+                            .map_or_else(SystemTime::now, |ts| ts.time(scan)),
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(latest)
+    }
+}
+
+impl Drop for IioBuffer {
+    fn drop(&mut self) {
+        let _ = fs::write(self.iio_path.join("buffer").join("enable"), "0");
+        disable_elements(&self.enabled);
+    }
+}
+
+// Switch off scan elements lumd turned on, the buffer must already be disabled
+fn disable_elements(enabled: &[PathBuf]) {
+    for path in enabled {
+        let _ = fs::write(path, "0");
+    }
+}
+
+// Attach a trigger if the device needs one: the configured trigger, or else one of
+// the sensor's own. Triggers of other devices are left alone, sharing them would
+// tie the sensor to their timing.
+fn setup_trigger(log: &Logger, iio_path: &Path, configured: Option<&str>) -> Result<()> {
+    let current = iio_path.join("trigger").join("current_trigger");
+    if !current.exists() || !fs::read_to_string(&current)?.trim().is_empty() {
+        return Ok(());
+    }
+
+    let device_name = fs::read_to_string(iio_path.join("name"))
+        .map(|n| n.trim().to_string())
+        .unwrap_or_default();
+
+    let mut triggers = Vec::new();
+    for entry in fs::read_dir("/sys/bus/iio/devices/")? {
+        let path = entry?.path();
+        let is_trigger = path
+            .file_name()
+            .is_some_and(|n| n.to_string_lossy().starts_with("trigger"));
+        if is_trigger && let Ok(name) = fs::read_to_string(path.join("name")) {
+            triggers.push(name.trim().to_string());
+        }
+    }
+
+    let trigger = match configured {
+        Some(wanted) => triggers
+            .iter()
+            .find(|name| *name == wanted)
+            .ok_or_else(|| {
+                LumdError::DeviceNotFound(format!("Configured IIO trigger {} not found", wanted))
+            })?,
+        None => triggers
+            .iter()
+            .find(|name| !device_name.is_empty() && name.starts_with(&device_name))
+            .ok_or_else(|| {
+                LumdError::DeviceNotFound(
+                    "Sensor has no trigger of its own, set [sensor] trigger to use another".into(),
+                )
+            })?,
+    };
+    info!(log, "Attaching IIO trigger"; "trigger" => trigger);
+    fs::write(current, trigger)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_type_decodes_shifted_values() {
+        let le = ScanType::parse("le:u12/16>>4\n").unwrap();
+        assert_eq!(le.decode(&[0x30, 0x12]), 0x123);
+
+        let be = ScanType::parse("be:s16/16>>0").unwrap();
+        assert_eq!(be.decode(&[0xff, 0xfe]), -2);
+    }
+
+    #[test]
+    fn scan_type_rejects_unusable_layouts() {
+        for s in [
+            "le:u0/16>>0",
+            "le:u16/16>>16",
+            "le:u12/16>>8",
+            "le:u12/12>>0",
+            "le:u16/0>>0",
+            "le:u64/128>>0",
+            "le:u16/16X2>>0",
+            "le:x16/16>>0",
+        ] {
+            assert!(ScanType::parse(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn timestamp_is_aligned_to_eight_bytes() {
+        let u16 = ScanType::parse("le:u16/16>>0").unwrap();
+        let s64 = ScanType::parse("le:s64/64>>0").unwrap();
+        let (layout, size) = scan_layout(vec![
+            (3, "in_timestamp".into(), s64),
+            (0, "in_illuminance".into(), u16),
+            (1, "in_intensity_ir".into(), u16),
+        ]);
+        let offsets: Vec<(&str, usize)> = layout
+            .iter()
+            .map(|(name, offset, _)| (name.as_str(), *offset))
+            .collect();
+        assert_eq!(
+            offsets,
+            [
+                ("in_illuminance", 0),
+                ("in_intensity_ir", 2),
+                ("in_timestamp", 8)
+            ]
+        );
+        assert_eq!(size, 16);
+    }
+
+    #[test]
+    fn timestamp_gives_the_scan_time() {
+        let timestamp = Timestamp {
+            offset: 0,
+            scan_type: ScanType::parse("le:s64/64>>0").unwrap(),
+            clock: ClockId::CLOCK_MONOTONIC,
+        };
+        let taken = clock_gettime(ClockId::CLOCK_MONOTONIC)
+            .unwrap()
+            .num_nanoseconds()
+            - 2_000_000_000;
+        let age = SystemTime::now()
+            .duration_since(timestamp.time(&taken.to_le_bytes()))
+            .unwrap();
+        assert!((age.as_secs_f32() - 2.0).abs() < 0.1, "{:?}", age);
+    }
+}
@@ -1,40 +1,169 @@
+use crate::bus::{EventBus, EventKind, Subscription};
 use crate::error::{LumdError, Result};
-use slog::{Logger, error, info, warn};
+use crate::mode::Mode;
+use serde_json::{Value, json};
+use slog::{Logger, debug, error, info, warn};
 use std::{
     fs, io,
-    io::Read,
-    os::unix::{fs::PermissionsExt, net::UnixListener},
+    io::{BufRead, BufReader, Write},
+    net::Shutdown,
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
     path::PathBuf,
     sync::{
         Arc,
         atomic::{AtomicBool, Ordering},
-        mpsc::Sender,
+        mpsc::{self, Sender},
     },
     thread,
     time::Duration,
 };
 
+// Version of the JSON protocol, reported by `hello` and checked against a request's `v`
+pub const PROTOCOL_VERSION: u64 = 1;
+
+// How long a client waits for the sampler loop to answer
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A brightness or step size, in raw units or percent of the primary output's maximum
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Amount {
+    Raw(i32),
+    Percent(f32),
+}
+
+impl Amount {
+    pub fn to_raw(self, max_brightness: i32) -> i32 {
+        match self {
+            Amount::Raw(raw) => raw,
+            Amount::Percent(percent) => (percent / 100.0 * max_brightness as f32).round() as i32,
+        }
+    }
+}
+
+// Manual adjustments jump to the new brightness unless `instant` is false,
+// in which case they fade like an ambient light change
 #[derive(Debug)]
 pub enum LumdCommand {
     Resample,
-    BrightnessUp,
-    BrightnessDown,
+    // Step by the amount, or by manual_adjustment_amount when None
+    BrightnessUp { step: Option<Amount>, instant: bool },
+    BrightnessDown { step: Option<Amount>, instant: bool },
+    SetBrightness { value: Amount, instant: bool },
+    SetOffset { offset: i32, instant: bool },
+    // Report the learned samples and what they mean for each output
+    Learned,
+    ResetLearned,
+    // Switch to a named profile, or back to the base settings with None
+    SetProfile(Option<String>),
+    SetMode(Mode),
+    // Report what the sampler loop is doing
+    Status,
+    // Report the settings in effect, with the named and power profiles applied
+    Config,
+    LightChanged,
+    BrightnessChanged,
     Shutdown,
 }
 
+// Error codes sent back in `error.code`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    // The line is not valid JSON
+    Parse,
+    // Valid JSON, but not a request object
+    InvalidRequest,
+    UnsupportedVersion,
+    UnknownCommand,
+    InvalidArgs,
+    // lumd understood the command but could not carry it out
+    Failed,
+    // The sampler loop did not answer
+    Unavailable,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Parse => "parse_error",
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::UnsupportedVersion => "unsupported_version",
+            ErrorCode::UnknownCommand => "unknown_command",
+            ErrorCode::InvalidArgs => "invalid_args",
+            ErrorCode::Failed => "failed",
+            ErrorCode::Unavailable => "unavailable",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CommandError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl CommandError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+// Outcome of a command: the `result` object, or why it failed
+pub type Reply = std::result::Result<Value, CommandError>;
+
+// A command for the sampler loop, with a channel for its outcome when a client waits for it
+#[derive(Debug)]
+pub struct Request {
+    pub command: LumdCommand,
+    pub reply: Option<Sender<Reply>>,
+}
+
+impl Request {
+    // Send the outcome to the waiting client, if there is one
+    pub fn respond(reply: Option<Sender<Reply>>, outcome: Reply) {
+        if let Some(reply) = reply {
+            // The client may have given up waiting
+            let _ = reply.send(outcome);
+        }
+    }
+}
+
+impl From<LumdCommand> for Request {
+    fn from(command: LumdCommand) -> Self {
+        Self {
+            command,
+            reply: None,
+        }
+    }
+}
+
+// What a JSON request asks for
+enum Call {
+    Hello,
+    // Stream these kinds of events over the connection
+    Subscribe(Vec<EventKind>),
+    Command(LumdCommand),
+}
+
 pub fn socket_server(
     log: Logger,
     socket_path: PathBuf,
-    trigger_tx: Sender<LumdCommand>,
+    trigger_tx: Sender<Request>,
+    bus: EventBus,
     running: Arc<AtomicBool>,
 ) -> Result<()> {
     // Ensure socket directory exists
-    if let Some(parent) = socket_path.parent() {
-        if !parent.exists() {
-            fs::create_dir_all(parent).map_err(|e| {
-                LumdError::InvalidData(format!("Failed to create socket directory: {}", e))
-            })?;
-        }
+    if let Some(parent) = socket_path.parent()
+        && !parent.exists()
+    {
+        fs::create_dir_all(parent).map_err(|e| {
+            LumdError::InvalidData(format!("Failed to create socket directory: {}", e))
+        })?;
     }
 
     // Remove existing socket if it exists
@@ -67,38 +196,16 @@ pub fn socket_server(
 
     while running.load(Ordering::SeqCst) {
         match listener.accept() {
-            Ok((mut stream, _addr)) => {
-                let mut buf = [0u8; 1024];
-                if let Ok(n) = stream.read(&mut buf) {
-                    let cmd = String::from_utf8_lossy(&buf[..n]);
-                    match cmd.trim() {
-                        "up" => {
-                            info!(log, "Command received: increase backlight");
-                            trigger_tx.send(LumdCommand::BrightnessUp).map_err(|_| {
-                                LumdError::Communication("Channel send error".into())
-                            })?;
-                        }
-                        "down" => {
-                            info!(log, "Command received: decrease backlight");
-                            trigger_tx.send(LumdCommand::BrightnessDown).map_err(|_| {
-                                LumdError::Communication("Channel send error".into())
-                            })?;
-                        }
-                        "resample" => {
-                            info!(log, "Command received: resample now");
-                            trigger_tx.send(LumdCommand::Resample).map_err(|_| {
-                                LumdError::Communication("Channel send error".into())
-                            })?;
-                        }
-                        "shutdown" => {
-                            info!(log, "Command received: shutdown");
-                            trigger_tx.send(LumdCommand::Shutdown).map_err(|_| {
-                                LumdError::Communication("Channel send error".into())
-                            })?;
-                        }
-                        _ => warn!(log, "Unknown command received"; "command" => cmd.trim()),
+            Ok((stream, _addr)) => {
+                // Clients may keep the connection open, so each gets its own thread
+                let log = log.clone();
+                let trigger_tx = trigger_tx.clone();
+                let bus = bus.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve_client(&log, stream, &trigger_tx, &bus) {
+                        debug!(log, "Client connection ended"; "error" => %e);
                     }
-                }
+                });
             }
             Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                 // No connection available, sleep a bit and try again
@@ -117,3 +224,439 @@ pub fn socket_server(
 
     Ok(())
 }
+
+// Answer each line a client sends: JSON requests get a JSON reply, plain words
+// from older clients are carried out without one
+fn serve_client(
+    log: &Logger,
+    stream: UnixStream,
+    trigger_tx: &Sender<Request>,
+    bus: &EventBus,
+) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if !line.starts_with(['{', '[']) {
+            match legacy_command(line) {
+                Some(command) => {
+                    info!(log, "Command received"; "command" => ?command);
+                    trigger_tx
+                        .send(command.into())
+                        .map_err(|_| LumdError::Communication("Channel send error".into()))?;
+                }
+                None => warn!(log, "Unknown command received"; "command" => line),
+            }
+            continue;
+        }
+
+        let mut subscription = None;
+        let (id, outcome) = match serde_json::from_str::<Value>(line) {
+            Ok(request) => {
+                let id = request.get("id").cloned().unwrap_or(Value::Null);
+                let outcome = parse_request(&request).and_then(|call| match call {
+                    Call::Hello => Ok(json!({
+                        "protocol": PROTOCOL_VERSION,
+                        "version": env!("CARGO_PKG_VERSION"),
+                    })),
+                    Call::Subscribe(kinds) => {
+                        let names: Vec<&str> = kinds.iter().map(EventKind::name).collect();
+                        info!(log, "Client subscribed"; "events" => ?names);
+                        let result = json!({ "events": names });
+                        subscription = Some(bus.subscribe(kinds));
+                        Ok(result)
+                    }
+                    Call::Command(command) => run_command(log, command, trigger_tx),
+                });
+                (id, outcome)
+            }
+            Err(e) => (
+                Value::Null,
+                Err(CommandError::new(ErrorCode::Parse, e.to_string())),
+            ),
+        };
+        let response = match outcome {
+            Ok(result) => json!({"v": PROTOCOL_VERSION, "id": id, "ok": true, "result": result}),
+            Err(e) => {
+                debug!(log, "Request failed"; "code" => e.code.as_str(), "message" => &e.message);
+                json!({
+                    "v": PROTOCOL_VERSION,
+                    "id": id,
+                    "ok": false,
+                    "error": {"code": e.code.as_str(), "message": e.message},
+                })
+            }
+        };
+        writeln!(writer, "{}", response)?;
+
+        // A subscription keeps the connection until the client goes away
+        if let Some(subscription) = subscription {
+            return stream_events(log, bus, subscription, writer);
+        }
+    }
+    Ok(())
+}
+
+// Send events to a subscribed client until it disconnects. Nothing it sends is
+// answered any more, the socket is only read to notice it leaving between events.
+fn stream_events(
+    log: &Logger,
+    bus: &EventBus,
+    subscription: Subscription,
+    mut writer: UnixStream,
+) -> Result<()> {
+    let id = subscription.id;
+    let mut reader = writer.try_clone()?;
+    let watcher_bus = bus.clone();
+    thread::spawn(move || {
+        let _ = io::copy(&mut reader, &mut io::sink());
+        watcher_bus.unsubscribe(id);
+    });
+
+    let result = subscription
+        .events
+        .iter()
+        .try_for_each(|event| writeln!(writer, "{}", event));
+    // Wake the watcher if writing failed while the client still looked connected
+    let _ = writer.shutdown(Shutdown::Both);
+    bus.unsubscribe(id);
+    info!(log, "Client unsubscribed");
+    Ok(result?)
+}
+
+// Plain-word commands sent by clients from before the JSON protocol
+fn legacy_command(line: &str) -> Option<LumdCommand> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["up"] => Some(LumdCommand::BrightnessUp {
+            step: None,
+            instant: true,
+        }),
+        ["down"] => Some(LumdCommand::BrightnessDown {
+            step: None,
+            instant: true,
+        }),
+        ["resample"] => Some(LumdCommand::Resample),
+        ["learned-reset"] => Some(LumdCommand::ResetLearned),
+        ["shutdown"] => Some(LumdCommand::Shutdown),
+        ["profile", "set", name] => Some(LumdCommand::SetProfile(Some(name.to_string()))),
+        ["profile", "clear"] => Some(LumdCommand::SetProfile(None)),
+        ["mode", "auto"] => Some(LumdCommand::SetMode(Mode::Auto)),
+        ["mode", "hold"] => Some(LumdCommand::SetMode(Mode::hold(None))),
+        ["mode", "hold", secs] => secs
+            .parse()
+            .ok()
+            .map(|secs| LumdCommand::SetMode(Mode::hold(Some(Duration::from_secs(secs))))),
+        ["mode", "pause"] => Some(LumdCommand::SetMode(Mode::Paused)),
+        _ => None,
+    }
+}
+
+// Hand a command to the sampler loop and wait for its answer
+fn run_command(log: &Logger, command: LumdCommand, trigger_tx: &Sender<Request>) -> Reply {
+    info!(log, "Command received"; "command" => ?command);
+    let (reply_tx, reply_rx) = mpsc::channel();
+    trigger_tx
+        .send(Request {
+            command,
+            reply: Some(reply_tx),
+        })
+        .map_err(|_| CommandError::new(ErrorCode::Unavailable, "lumd is shutting down"))?;
+    reply_rx
+        .recv_timeout(REPLY_TIMEOUT)
+        .map_err(|_| CommandError::new(ErrorCode::Unavailable, "lumd did not answer in time"))?
+}
+
+// Check a request object and turn it into a command:
+// {"v": 1, "id": 7, "cmd": "mode", "args": {"mode": "hold", "secs": 600}}
+fn parse_request(request: &Value) -> std::result::Result<Call, CommandError> {
+    let invalid = |message: &str| CommandError::new(ErrorCode::InvalidRequest, message);
+    let request = request
+        .as_object()
+        .ok_or_else(|| invalid("Request must be a JSON object"))?;
+
+    if let Some(v) = request.get("v") {
+        let v = v
+            .as_u64()
+            .ok_or_else(|| invalid("\"v\" must be a protocol version number"))?;
+        if v != PROTOCOL_VERSION {
+            return Err(CommandError::new(
+                ErrorCode::UnsupportedVersion,
+                format!(
+                    "Protocol version {} is not supported, lumd speaks {}",
+                    v, PROTOCOL_VERSION
+                ),
+            ));
+        }
+    }
+
+    let cmd = request
+        .get("cmd")
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid("\"cmd\" must be a string"))?;
+    let empty = serde_json::Map::new();
+    let args = match request.get("args") {
+        None | Some(Value::Null) => &empty,
+        Some(Value::Object(args)) => args,
+        Some(_) => return Err(invalid("\"args\" must be an object")),
+    };
+    let bad_args = |message: String| CommandError::new(ErrorCode::InvalidArgs, message);
+
+    let command = match cmd {
+        "hello" => return Ok(Call::Hello),
+        "subscribe" => {
+            let kinds = match args.get("events") {
+                None | Some(Value::Null) => EventKind::ALL.to_vec(),
+                Some(Value::Array(names)) => names
+                    .iter()
+                    .map(|name| {
+                        name.as_str()
+                            .ok_or_else(|| format!("Event names must be strings, got {}", name))
+                            .and_then(|name| EventKind::from_str(name).map_err(|e| e.to_string()))
+                    })
+                    .collect::<std::result::Result<_, _>>()
+                    .map_err(bad_args)?,
+                Some(_) => return Err(bad_args("\"events\" must be a list of names".into())),
+            };
+            return Ok(Call::Subscribe(kinds));
+        }
+        "up" => LumdCommand::BrightnessUp {
+            step: parse_amount(args)?,
+            instant: parse_instant(args)?,
+        },
+        "down" => LumdCommand::BrightnessDown {
+            step: parse_amount(args)?,
+            instant: parse_instant(args)?,
+        },
+        "set" => {
+            let value = parse_amount(args)?
+                .ok_or_else(|| bad_args("\"set\" needs \"percent\" or \"raw\"".into()))?;
+            let in_range = match value {
+                Amount::Raw(raw) => raw >= 0,
+                Amount::Percent(percent) => (0.0..=100.0).contains(&percent),
+            };
+            if !in_range {
+                return Err(bad_args(
+                    "Brightness must be 0-100 percent or a raw value of at least 0".into(),
+                ));
+            }
+            LumdCommand::SetBrightness {
+                value,
+                instant: parse_instant(args)?,
+            }
+        }
+        "offset" => {
+            let offset = args
+                .get("offset")
+                .and_then(Value::as_i64)
+                .and_then(|o| i32::try_from(o).ok())
+                .ok_or_else(|| bad_args("\"offset\" must be a whole number".into()))?;
+            LumdCommand::SetOffset {
+                offset,
+                instant: parse_instant(args)?,
+            }
+        }
+        "resample" => LumdCommand::Resample,
+        "status" => LumdCommand::Status,
+        "config" => LumdCommand::Config,
+        "learned" => LumdCommand::Learned,
+        "learned-reset" => LumdCommand::ResetLearned,
+        "shutdown" => LumdCommand::Shutdown,
+        "profile" => match args.get("name") {
+            None | Some(Value::Null) => LumdCommand::SetProfile(None),
+            Some(Value::String(name)) => LumdCommand::SetProfile(Some(name.clone())),
+            Some(other) => {
+                return Err(bad_args(format!(
+                    "Profile name must be a string or null, got {}",
+                    other
+                )));
+            }
+        },
+        "mode" => {
+            let secs = match args.get("secs") {
+                None | Some(Value::Null) => None,
+                Some(secs) => Some(secs.as_u64().ok_or_else(|| {
+                    bad_args(format!("Hold seconds must be a whole number, got {}", secs))
+                })?),
+            };
+            match args.get("mode").and_then(Value::as_str) {
+                Some("auto") => LumdCommand::SetMode(Mode::Auto),
+                Some("hold") => LumdCommand::SetMode(Mode::hold(secs.map(Duration::from_secs))),
+                Some("pause") => LumdCommand::SetMode(Mode::Paused),
+                _ => {
+                    return Err(bad_args(
+                        "Mode must be \"auto\", \"hold\" or \"pause\"".into(),
+                    ));
+                }
+            }
+        }
+        other => {
+            return Err(CommandError::new(
+                ErrorCode::UnknownCommand,
+                format!("Unknown command: {}", other),
+            ));
+        }
+    };
+    Ok(Call::Command(command))
+}
+
+// Brightness given as {"raw": 1200} or {"percent": 40}, None when neither is set
+fn parse_amount(
+    args: &serde_json::Map<String, Value>,
+) -> std::result::Result<Option<Amount>, CommandError> {
+    let bad_args = |message: &str| CommandError::new(ErrorCode::InvalidArgs, message);
+    match (args.get("raw"), args.get("percent")) {
+        (Some(_), Some(_)) => Err(bad_args("Give either \"raw\" or \"percent\", not both")),
+        (Some(raw), None) => raw
+            .as_i64()
+            .and_then(|raw| i32::try_from(raw).ok())
+            .map(|raw| Some(Amount::Raw(raw)))
+            .ok_or_else(|| bad_args("\"raw\" must be a whole number")),
+        (None, Some(percent)) => percent
+            .as_f64()
+            .filter(|p| p.is_finite())
+            .map(|p| Some(Amount::Percent(p as f32)))
+            .ok_or_else(|| bad_args("\"percent\" must be a number")),
+        (None, None) => Ok(None),
+    }
+}
+
+// Whether to jump to the new brightness (the default) or fade to it
+fn parse_instant(args: &serde_json::Map<String, Value>) -> std::result::Result<bool, CommandError> {
+    match args.get("instant") {
+        None | Some(Value::Null) => Ok(true),
+        Some(instant) => instant.as_bool().ok_or_else(|| {
+            CommandError::new(ErrorCode::InvalidArgs, "\"instant\" must be true or false")
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(request: Value) -> LumdCommand {
+        match parse_request(&request) {
+            Ok(Call::Command(command)) => command,
+            Ok(_) => panic!("{} is not a command", request),
+            Err(e) => panic!("{} was refused: {}", request, e.message),
+        }
+    }
+
+    fn refused(request: Value) -> ErrorCode {
+        match parse_request(&request) {
+            Ok(_) => panic!("{} was accepted", request),
+            Err(e) => e.code,
+        }
+    }
+
+    #[test]
+    fn parses_brightness_arguments() {
+        assert!(matches!(
+            command(json!({"v": 1, "cmd": "up"})),
+            LumdCommand::BrightnessUp {
+                step: None,
+                instant: true
+            }
+        ));
+        assert!(matches!(
+            command(json!({"cmd": "down", "args": {"percent": 5, "instant": false}})),
+            LumdCommand::BrightnessDown {
+                step: Some(Amount::Percent(5.0)),
+                instant: false
+            }
+        ));
+        assert!(matches!(
+            command(json!({"cmd": "set", "args": {"raw": 1200}})),
+            LumdCommand::SetBrightness {
+                value: Amount::Raw(1200),
+                instant: true
+            }
+        ));
+        assert!(matches!(
+            command(json!({"cmd": "offset", "args": {"offset": -40}})),
+            LumdCommand::SetOffset { offset: -40, .. }
+        ));
+        assert!(matches!(
+            command(json!({"cmd": "mode", "args": {"mode": "hold", "secs": 60}})),
+            LumdCommand::SetMode(Mode::Manual { until: Some(_) })
+        ));
+        assert!(matches!(
+            command(json!({"cmd": "profile", "args": {"name": null}})),
+            LumdCommand::SetProfile(None)
+        ));
+    }
+
+    #[test]
+    fn refuses_bad_requests() {
+        assert_eq!(refused(json!([1])), ErrorCode::InvalidRequest);
+        assert_eq!(refused(json!({"args": {}})), ErrorCode::InvalidRequest);
+        assert_eq!(
+            refused(json!({"cmd": "up", "args": [1]})),
+            ErrorCode::InvalidRequest
+        );
+        assert_eq!(
+            refused(json!({"v": 2, "cmd": "status"})),
+            ErrorCode::UnsupportedVersion
+        );
+        assert_eq!(refused(json!({"cmd": "dance"})), ErrorCode::UnknownCommand);
+        for args in [
+            json!({"raw": 10, "percent": 10}),
+            json!({"percent": 150}),
+            json!({"raw": -1}),
+            json!({"raw": 1.5}),
+            json!({}),
+            json!({"raw": 10, "instant": "yes"}),
+        ] {
+            assert_eq!(
+                refused(json!({"cmd": "set", "args": args})),
+                ErrorCode::InvalidArgs,
+                "{}",
+                args
+            );
+        }
+        assert_eq!(
+            refused(json!({"cmd": "mode", "args": {"mode": "sleep"}})),
+            ErrorCode::InvalidArgs
+        );
+        assert_eq!(
+            refused(json!({"cmd": "subscribe", "args": {"events": ["lux", "rain"]}})),
+            ErrorCode::InvalidArgs
+        );
+    }
+
+    #[test]
+    fn subscribe_defaults_to_every_event() {
+        match parse_request(&json!({"cmd": "subscribe"})) {
+            Ok(Call::Subscribe(kinds)) => assert_eq!(kinds, EventKind::ALL.to_vec()),
+            _ => panic!("subscribe was not parsed"),
+        }
+        match parse_request(&json!({"cmd": "subscribe", "args": {"events": ["mode"]}})) {
+            Ok(Call::Subscribe(kinds)) => assert_eq!(kinds, vec![EventKind::Mode]),
+            _ => panic!("subscribe was not parsed"),
+        }
+    }
+
+    #[test]
+    fn legacy_words_still_work() {
+        assert!(matches!(
+            legacy_command("up"),
+            Some(LumdCommand::BrightnessUp { step: None, .. })
+        ));
+        assert!(matches!(
+            legacy_command("mode hold 30"),
+            Some(LumdCommand::SetMode(Mode::Manual { .. }))
+        ));
+        assert!(matches!(
+            legacy_command("profile set office"),
+            Some(LumdCommand::SetProfile(Some(name))) if name == "office"
+        ));
+        assert!(legacy_command("mode hold soon").is_none());
+        assert!(legacy_command("brighter").is_none());
+    }
+}
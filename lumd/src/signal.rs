@@ -1,11 +1,17 @@
 use crate::error::Result;
+use crate::server::{LumdCommand, Request};
 use signal_hook::{consts::signal::*, iterator::Signals};
 use slog::{Logger, info, warn};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
 use std::thread;
 
-pub fn setup_signal_handler(logger: Logger, running: Arc<AtomicBool>) -> Result<()> {
+pub fn setup_signal_handler(
+    logger: Logger,
+    running: Arc<AtomicBool>,
+    tx: Sender<Request>,
+) -> Result<()> {
     let mut signals = Signals::new([SIGINT, SIGTERM])
         .map_err(std::io::Error::other)?;
 
@@ -17,6 +23,8 @@ pub fn setup_signal_handler(logger: Logger, running: Arc<AtomicBool>) -> Result<
                 SIGINT | SIGTERM => {
                     info!(logger, "Received signal {}, initiating shutdown", sig);
                     running.store(false, Ordering::SeqCst);
+                    // Wake the sampler loop, it may be waiting for minutes
+                    let _ = tx.send(LumdCommand::Shutdown.into());
                     break;
                 }
                 _ => warn!(logger, "Unexpected signal: {}", sig),
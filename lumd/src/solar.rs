@@ -0,0 +1,202 @@
+use crate::error::{LumdError, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Sun elevation (degrees) where civil twilight ends and night begins
+const CIVIL_TWILIGHT: f64 = -6.0;
+
+// How the schedule combines with an ambient light sensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScheduleBlend {
+    // Only used on machines without a sensor
+    #[default]
+    Fallback,
+    // The sensor target never exceeds the schedule
+    Cap,
+    // The sensor target is pulled towards the schedule by `bias_weight`
+    Bias,
+}
+
+impl ScheduleBlend {
+    pub fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "fallback" => Ok(ScheduleBlend::Fallback),
+            "cap" => Ok(ScheduleBlend::Cap),
+            "bias" => Ok(ScheduleBlend::Bias),
+            _ => Err(LumdError::InvalidData(format!(
+                "Unknown schedule blend: {} (expected fallback, cap or bias)",
+                s
+            ))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScheduleBlend::Fallback => "fallback",
+            ScheduleBlend::Cap => "cap",
+            ScheduleBlend::Bias => "bias",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduleConfig {
+    // Location in degrees, north and east positive; the schedule is off without it
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    // Curve percentage in full daylight and at night
+    pub day_percent: f32,
+    pub night_percent: f32,
+    // Sun elevation in degrees at which full daylight is reached
+    pub full_day_elevation: f64,
+    pub blend: ScheduleBlend,
+    // Share of the schedule in the target with `blend = "bias"`
+    pub bias_weight: f32,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            latitude: None,
+            longitude: None,
+            day_percent: 80.0,
+            night_percent: 20.0,
+            full_day_elevation: 10.0,
+            blend: ScheduleBlend::default(),
+            bias_weight: 0.3,
+        }
+    }
+}
+
+// Day curve computed from the sun's position at a fixed location, no network needed.
+// Brightness stays at `night_percent` until the end of civil twilight, rises with the
+// sun through sunrise and reaches `day_percent` at `full_day_elevation`; the evening
+// mirrors it.
+#[derive(Debug, Clone)]
+pub struct SolarSchedule {
+    config: ScheduleConfig,
+    latitude: f64,
+    longitude: f64,
+}
+
+impl SolarSchedule {
+    // The schedule for `config`, or None when no location is configured
+    pub fn new(config: &ScheduleConfig) -> Option<Self> {
+        Some(Self {
+            config: config.clone(),
+            latitude: config.latitude?,
+            longitude: config.longitude?,
+        })
+    }
+
+    pub fn blend(&self) -> ScheduleBlend {
+        self.config.blend
+    }
+
+    // Sun elevation in degrees right now
+    pub fn elevation_now(&self) -> f64 {
+        let unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        sun_elevation(unix, self.latitude, self.longitude)
+    }
+
+    // Curve percentage for a sun elevation
+    pub fn percent_at(&self, elevation: f64) -> f32 {
+        let span = (self.config.full_day_elevation - CIVIL_TWILIGHT).max(f64::EPSILON);
+        let t = ((elevation - CIVIL_TWILIGHT) / span).clamp(0.0, 1.0) as f32;
+        // Smoothstep keeps the dawn and dusk ramps gentle at both ends
+        let t = t * t * (3.0 - 2.0 * t);
+        self.config.night_percent + (self.config.day_percent - self.config.night_percent) * t
+    }
+
+    // Combine a sensor-derived percentage with the schedule's
+    pub fn apply(&self, sensor_percent: f32, schedule_percent: f32) -> f32 {
+        match self.config.blend {
+            ScheduleBlend::Fallback => sensor_percent,
+            ScheduleBlend::Cap => sensor_percent.min(schedule_percent),
+            ScheduleBlend::Bias => {
+                let weight = self.config.bias_weight.clamp(0.0, 1.0);
+                sensor_percent + (schedule_percent - sensor_percent) * weight
+            }
+        }
+    }
+}
+
+// Solar elevation in degrees from the low-precision almanac formulas, good to
+// a fraction of a degree which is plenty for a brightness curve
+fn sun_elevation(unix_secs: f64, latitude: f64, longitude: f64) -> f64 {
+    // Days since J2000.0
+    let n = unix_secs / 86400.0 + 2440587.5 - 2451545.0;
+
+    let mean_longitude = (280.460 + 0.9856474 * n).rem_euclid(360.0);
+    let mean_anomaly = (357.528 + 0.9856003 * n).rem_euclid(360.0).to_radians();
+    let ecliptic_longitude =
+        (mean_longitude + 1.915 * mean_anomaly.sin() + 0.020 * (2.0 * mean_anomaly).sin())
+            .to_radians();
+    let obliquity = (23.439 - 0.0000004 * n).to_radians();
+
+    let right_ascension = (obliquity.cos() * ecliptic_longitude.sin())
+        .atan2(ecliptic_longitude.cos())
+        .to_degrees();
+    let declination = (obliquity.sin() * ecliptic_longitude.sin()).asin();
+
+    // Greenwich mean sidereal time in degrees, then the local hour angle
+    let sidereal = (280.46061837 + 360.98564736629 * n).rem_euclid(360.0);
+    let hour_angle = (sidereal + longitude - right_ascension).to_radians();
+
+    let latitude = latitude.to_radians();
+    (latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos())
+        .asin()
+        .to_degrees()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-06-21 12:00 UTC, the June solstice at Greenwich noon
+    const SOLSTICE_NOON: f64 = 1718971200.0;
+
+    fn schedule(blend: ScheduleBlend) -> SolarSchedule {
+        SolarSchedule::new(&ScheduleConfig {
+            latitude: Some(51.48),
+            longitude: Some(0.0),
+            blend,
+            ..ScheduleConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn elevation_matches_known_sun_positions() {
+        // Noon altitude at the solstice is 90 - latitude + axial tilt
+        let noon = sun_elevation(SOLSTICE_NOON, 51.48, 0.0);
+        assert!((noon - 61.96).abs() < 0.5, "{}", noon);
+        // Midnight at Greenwich stays below the horizon
+        let midnight = sun_elevation(SOLSTICE_NOON - 43200.0, 51.48, 0.0);
+        assert!((midnight + 15.0).abs() < 0.5, "{}", midnight);
+        // Half a world east it is midnight
+        assert!(sun_elevation(SOLSTICE_NOON, 51.48, 180.0) < 0.0);
+    }
+
+    #[test]
+    fn percent_ramps_between_night_and_day() {
+        let s = schedule(ScheduleBlend::Fallback);
+        assert_eq!(s.percent_at(-30.0), 20.0);
+        assert_eq!(s.percent_at(CIVIL_TWILIGHT), 20.0);
+        assert_eq!(s.percent_at(2.0), 50.0);
+        assert_eq!(s.percent_at(10.0), 80.0);
+        assert_eq!(s.percent_at(60.0), 80.0);
+        assert!(s.percent_at(-1.0) < s.percent_at(5.0));
+    }
+
+    #[test]
+    fn blend_combines_sensor_and_schedule() {
+        assert_eq!(schedule(ScheduleBlend::Fallback).apply(70.0, 20.0), 70.0);
+        assert_eq!(schedule(ScheduleBlend::Cap).apply(70.0, 20.0), 20.0);
+        assert_eq!(schedule(ScheduleBlend::Cap).apply(10.0, 20.0), 10.0);
+        assert!((schedule(ScheduleBlend::Bias).apply(70.0, 20.0) - 55.0).abs() < 1e-4);
+        assert!(SolarSchedule::new(&ScheduleConfig::default()).is_none());
+    }
+}
@@ -0,0 +1,172 @@
+use crate::backlight::Output;
+use crate::error::{LumdError, Result};
+use crate::mode::Mode;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Runtime state kept across restarts in `$XDG_STATE_HOME/lumd/state.toml`.
+#[derive(Debug, Clone)]
+pub struct RuntimeState {
+    path: PathBuf,
+    pub offset: Option<i32>,
+    pub mode: Option<String>,
+    // End of a timed manual hold, in seconds since the epoch
+    pub hold_until: Option<u64>,
+    pub profile: Option<String>,
+    // Last brightness lumd set, by output name
+    pub brightness: BTreeMap<String, i32>,
+    // Brightness found before lumd took over, kept until a clean shutdown
+    pub original_brightness: BTreeMap<String, i32>,
+}
+
+fn read_brightness_table(parsed: &toml::Value, key: &str) -> Result<BTreeMap<String, i32>> {
+    let mut values = BTreeMap::new();
+    if let Some(table) = parsed.get(key).and_then(|v| v.as_table()) {
+        for (name, value) in table {
+            let value = value.as_integer().ok_or_else(|| {
+                LumdError::InvalidData(format!("{}.{} must be an integer", key, name))
+            })?;
+            values.insert(name.clone(), value as i32);
+        }
+    }
+    Ok(values)
+}
+
+impl RuntimeState {
+    // Create an empty state that will be saved to `path`
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            offset: None,
+            mode: None,
+            hold_until: None,
+            profile: None,
+            brightness: BTreeMap::new(),
+            original_brightness: BTreeMap::new(),
+        }
+    }
+
+    // Load the state from `path`, starting empty if the file does not exist
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut state = Self::new(path);
+
+        if !state.path.exists() {
+            return Ok(state);
+        }
+
+        let content = fs::read_to_string(&state.path)?;
+        let parsed: toml::Value = toml::from_str(&content)
+            .map_err(|e| LumdError::InvalidData(format!("State file parse error: {}", e)))?;
+
+        state.offset = parsed
+            .get("offset")
+            .and_then(|v| v.as_integer())
+            .map(|v| v as i32);
+        state.mode = parsed
+            .get("mode")
+            .and_then(|v| v.as_str())
+            .map(String::from);
+        state.hold_until = parsed
+            .get("hold_until")
+            .and_then(|v| v.as_integer())
+            .map(|v| v as u64);
+        state.profile = parsed
+            .get("profile")
+            .and_then(|v| v.as_str())
+            .map(String::from);
+        state.brightness = read_brightness_table(&parsed, "brightness")?;
+        state.original_brightness = read_brightness_table(&parsed, "original_brightness")?;
+
+        Ok(state)
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = Some(mode.name().to_string());
+        self.hold_until = mode.hold_until_secs();
+    }
+
+    // Remember the brightness lumd last set, or is fading to, on each output
+    pub fn record_outputs(&mut self, outputs: &[Output]) {
+        for output in outputs {
+            self.brightness
+                .insert(output.name.clone(), output.fader.target());
+        }
+    }
+
+    // Write the state to disk atomically
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent()
+            && !parent.exists()
+        {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut table = toml::Table::new();
+        if let Some(offset) = self.offset {
+            table.insert("offset".into(), offset.into());
+        }
+        if let Some(mode) = &self.mode {
+            table.insert("mode".into(), mode.as_str().into());
+        }
+        if let Some(hold_until) = self.hold_until {
+            table.insert("hold_until".into(), (hold_until as i64).into());
+        }
+        if let Some(profile) = &self.profile {
+            table.insert("profile".into(), profile.as_str().into());
+        }
+        for (key, values) in [
+            ("brightness", &self.brightness),
+            ("original_brightness", &self.original_brightness),
+        ] {
+            if values.is_empty() {
+                continue;
+            }
+            let values: toml::Table = values
+                .iter()
+                .map(|(name, &value)| (name.clone(), value.into()))
+                .collect();
+            table.insert(key.into(), values.into());
+        }
+
+        let content = toml::to_string(&table)
+            .map_err(|e| LumdError::InvalidData(format!("State serialization error: {}", e)))?;
+        let content = format!(
+            "# lumd runtime state, rewritten while lumd runs\n{}",
+            content
+        );
+        let tmp = self.path.with_extension("toml.tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = TempDir::new("state");
+        let path = dir.join("state.toml");
+        assert!(RuntimeState::load(&path).unwrap().offset.is_none());
+
+        let mut state = RuntimeState::new(&path);
+        state.offset = Some(-12);
+        state.set_mode(Mode::Paused);
+        // Characters Rust's Debug escaping writes differently from TOML
+        state.profile = Some("désk \"2\" \u{1b}\\".into());
+        state.brightness.insert("intel_backlight".into(), 420);
+        state.original_brightness.insert("odd \"name\"".into(), 7);
+        state.save().unwrap();
+
+        let loaded = RuntimeState::load(&path).unwrap();
+        assert_eq!(loaded.offset, Some(-12));
+        assert_eq!(loaded.mode.as_deref(), Some("paused"));
+        assert_eq!(loaded.profile, state.profile);
+        assert_eq!(loaded.brightness, state.brightness);
+        assert_eq!(loaded.original_brightness, state.original_brightness);
+    }
+}
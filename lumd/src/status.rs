@@ -0,0 +1,160 @@
+use crate::backlight::Output;
+use crate::device::read_brightness;
+use crate::mode::Mode;
+use crate::power::PowerProfile;
+use serde_json::{Value, json};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// What the sampler loop saw at its last sample
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SampleStatus {
+    // Sensor reading before and after smoothing
+    pub raw_lux: Option<f32>,
+    pub lux: Option<f32>,
+    pub time: Option<SystemTime>,
+}
+
+impl SampleStatus {
+    pub fn record(&mut self, raw_lux: Option<f32>, lux: Option<f32>, time: SystemTime) {
+        self.raw_lux = raw_lux;
+        self.lux = lux;
+        self.time = Some(time);
+    }
+}
+
+// Everything the `status` request reports
+pub struct Status<'a> {
+    pub sample: SampleStatus,
+    pub outputs: &'a [Output],
+    pub sensor: Option<&'a Path>,
+    pub offset: i32,
+    pub mode: Mode,
+    pub profile: Option<&'a str>,
+    pub power_profile: Option<PowerProfile>,
+}
+
+// Raw brightness as a percentage of the maximum
+pub fn percent(value: i32, max: i32) -> f64 {
+    if max > 0 {
+        value as f64 * 100.0 / max as f64
+    } else {
+        0.0
+    }
+}
+
+impl Status<'_> {
+    pub fn to_json(&self) -> Value {
+        let outputs: Vec<Value> = self
+            .outputs
+            .iter()
+            .map(|output| {
+                // Fall back to what lumd wrote when the device cannot be read
+                let current =
+                    read_brightness(&output.path).unwrap_or_else(|_| output.last_written.get());
+                let target = output.fader.target();
+                let max = output.limits.max_brightness;
+                json!({
+                    "name": output.name,
+                    "path": output.path.display().to_string(),
+                    "max_brightness": max,
+                    "current": current,
+                    "target": target,
+                    "current_percent": percent(current, max),
+                    "target_percent": percent(target, max),
+                })
+            })
+            .collect();
+
+        let last_sample = self
+            .sample
+            .time
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+
+        json!({
+            "lux": self.sample.raw_lux,
+            "filtered_lux": self.sample.lux,
+            "offset": self.offset,
+            "mode": self.mode.name(),
+            "hold_until": self.mode.hold_until_secs(),
+            "profile": self.profile,
+            "power_profile": self.power_profile.map(|p| p.name()),
+            "sensor": self.sensor.map(|p| p.display().to_string()),
+            "outputs": outputs,
+            "last_sample": last_sample,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn status_reports_every_field() {
+        let mut sample = SampleStatus::default();
+        sample.record(
+            Some(120.0),
+            Some(100.0),
+            UNIX_EPOCH + Duration::from_secs(1_700_000_100),
+        );
+
+        let hold_until = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let status = Status {
+            sample,
+            outputs: &[],
+            sensor: Some(Path::new("/sys/bus/iio/devices/iio:device0")),
+            offset: -20,
+            mode: Mode::Manual {
+                until: Some(hold_until),
+            },
+            profile: Some("office"),
+            power_profile: Some(PowerProfile::LowBattery),
+        };
+        let json = status.to_json();
+        assert_eq!(json["lux"], 120.0);
+        assert_eq!(json["filtered_lux"], 100.0);
+        assert_eq!(json["offset"], -20);
+        assert_eq!(json["mode"], "manual");
+        assert_eq!(json["hold_until"], 1_700_000_000);
+        assert_eq!(json["profile"], "office");
+        assert_eq!(json["power_profile"], "low-battery");
+        assert_eq!(json["sensor"], "/sys/bus/iio/devices/iio:device0");
+        assert_eq!(json["outputs"], json!([]));
+        assert_eq!(json["last_sample"], 1_700_000_100);
+    }
+
+    #[test]
+    fn missing_values_are_null() {
+        let status = Status {
+            sample: SampleStatus::default(),
+            outputs: &[],
+            sensor: None,
+            offset: 0,
+            mode: Mode::Auto,
+            profile: None,
+            power_profile: None,
+        };
+        let json = status.to_json();
+        for key in [
+            "lux",
+            "filtered_lux",
+            "hold_until",
+            "profile",
+            "power_profile",
+            "sensor",
+            "last_sample",
+        ] {
+            assert!(json[key].is_null(), "{}", key);
+        }
+        assert_eq!(json["mode"], "auto");
+    }
+
+    #[test]
+    fn percent_handles_unknown_maximum() {
+        assert_eq!(percent(250, 1000), 25.0);
+        assert_eq!(percent(250, 0), 0.0);
+    }
+}
@@ -0,0 +1,607 @@
+use crate::curve::MappingMode;
+use crate::device::{read_brightness, set_brightness};
+use crate::error::{LumdError, Result};
+use slog::{Logger, debug, error, info};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{
+    Arc, Mutex, MutexGuard,
+    mpsc::{self, RecvTimeoutError, Sender},
+};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Shape of a brightness transition over its duration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Easing {
+    // Constant speed
+    #[default]
+    Linear,
+    // Slow start and end, fastest in the middle
+    EaseInOut,
+    // Fast start settling exponentially onto the target
+    Exponential,
+}
+
+impl Easing {
+    pub fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "linear" => Ok(Easing::Linear),
+            "ease-in-out" | "ease_in_out" => Ok(Easing::EaseInOut),
+            "exponential" => Ok(Easing::Exponential),
+            _ => Err(LumdError::InvalidData(format!(
+                "Unknown easing: {} (expected linear, ease-in-out or exponential)",
+                s
+            ))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Easing::Linear => "linear",
+            Easing::EaseInOut => "ease-in-out",
+            Easing::Exponential => "exponential",
+        }
+    }
+
+    // Fraction of the distance covered at time fraction `t` (both 0..1).
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseInOut => t * t * (3.0 - 2.0 * t),
+            // 1 - 2^(-10t), rescaled so it ends exactly at 1
+            Easing::Exponential => (1.0 - (-10.0 * t).exp2()) / (1.0 - (-10.0f32).exp2()),
+        }
+    }
+}
+
+// Eye adaptation: brightness approaches its target exponentially, with separate
+// time constants for brightening and dimming
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptationConfig {
+    pub enabled: bool,
+    // Time constants in seconds; eyes adjust to light far faster than to darkness
+    pub rising_secs: f32,
+    pub falling_secs: f32,
+}
+
+impl Default for AdaptationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rising_secs: 0.5,
+            falling_secs: 10.0,
+        }
+    }
+}
+
+impl AdaptationConfig {
+    fn time_constant(&self, from: f32, to: f32) -> f32 {
+        let secs = if to > from {
+            self.rising_secs
+        } else {
+            self.falling_secs
+        };
+        secs.max(0.001)
+    }
+
+    // Time for the exponential approach to come within half a raw step of `to`
+    fn duration(&self, from: f32, to: f32) -> Duration {
+        let distance = (to - from).abs().max(0.5);
+        Duration::from_secs_f32(self.time_constant(from, to) * (distance / 0.5).ln())
+    }
+
+    // Fraction of the distance covered after `elapsed`
+    fn progress(&self, from: f32, to: f32, elapsed: Duration) -> f32 {
+        1.0 - (-elapsed.as_secs_f32() / self.time_constant(from, to)).exp()
+    }
+}
+
+// Timing of a brightness transition on one output
+#[derive(Debug, Clone, Copy)]
+pub struct TransitionTiming {
+    // Length of a transition across the full brightness range
+    pub duration: Duration,
+    // Interval between brightness writes
+    pub frame: Duration,
+    pub easing: Easing,
+    // Shorten transitions in proportion to the distance they cover
+    pub scale_by_distance: bool,
+    // Replaces the duration and easing when set
+    pub adaptation: Option<AdaptationConfig>,
+}
+
+impl TransitionTiming {
+    // Duration of a transition from `start` to `target` on an output with `max_brightness`
+    pub fn duration_for(&self, start: f32, target: i32, max_brightness: i32) -> Duration {
+        if let Some(adaptation) = &self.adaptation {
+            return adaptation.duration(start, target as f32);
+        }
+        if !self.scale_by_distance {
+            return self.duration;
+        }
+        let distance = (target as f32 - start).abs() / max_brightness.max(1) as f32;
+        self.duration.mul_f32(distance.min(1.0)).max(self.frame)
+    }
+}
+
+// The brightness lumd last wrote to an output.
+// The lock is held across each write and each comparison with the device, so a
+// value is only recorded once it has landed and a check never sees a write halfway.
+#[derive(Debug, Clone)]
+pub struct LastWritten(Arc<Mutex<i32>>);
+
+impl LastWritten {
+    pub fn new(value: i32) -> Self {
+        Self(Arc::new(Mutex::new(value)))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, i32> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn get(&self) -> i32 {
+        *self.lock()
+    }
+
+    // Write `value` to the backlight at `path` and record it
+    pub fn write(&self, path: &Path, value: i32) -> Result<()> {
+        let mut written = self.lock();
+        set_brightness(path, value)?;
+        *written = value;
+        Ok(())
+    }
+
+    // Write `value` unless the device no longer holds the last value lumd wrote,
+    // in which case the brightness found there is returned and nothing is written
+    pub fn write_unless_changed(&self, path: &Path, value: i32) -> Result<Option<i32>> {
+        let mut written = self.lock();
+        let current = read_brightness(path)?;
+        if current != *written {
+            return Ok(Some(current));
+        }
+        if value != current {
+            set_brightness(path, value)?;
+            *written = value;
+        }
+        Ok(None)
+    }
+
+    // Whether the device holds something other than the last value lumd wrote
+    pub fn differs(&self, path: &Path) -> Result<bool> {
+        let written = self.lock();
+        Ok(read_brightness(path)? != *written)
+    }
+
+    // Take a brightness set outside lumd as the last written value, returning
+    // the previous value and the new one if the device changed
+    pub fn adopt_external(&self, path: &Path) -> Result<Option<(i32, i32)>> {
+        let mut written = self.lock();
+        let current = read_brightness(path)?;
+        if current == *written {
+            return Ok(None);
+        }
+        let previous = std::mem::replace(&mut *written, current);
+        Ok(Some((previous, current)))
+    }
+
+    // Take whatever the device holds as the last written value, e.g. after a stretch
+    // where lumd did not write and a change is not worth reporting
+    pub fn resync(&self, path: &Path) -> Result<i32> {
+        let mut written = self.lock();
+        *written = read_brightness(path)?;
+        Ok(*written)
+    }
+}
+
+enum FadeCommand {
+    Target {
+        value: i32,
+        instant: bool,
+    },
+    // Switch timing and mapping mode after a profile change
+    Configure {
+        timing: TransitionTiming,
+        mode: MappingMode,
+    },
+    Stop,
+}
+
+// One leg of a fade, from wherever the output was when it was given a target
+struct Segment {
+    from: f32,
+    to: i32,
+    start: Instant,
+    duration: Duration,
+}
+
+// Transition actor owning the brightness writes of one backlight.
+// Targets can be changed at any time; a new target continues from the brightness
+// reached so far instead of restarting the fade.
+#[derive(Debug)]
+pub struct Fader {
+    tx: Sender<FadeCommand>,
+    handle: Option<JoinHandle<()>>,
+    // Latest requested brightness
+    target: Arc<AtomicI32>,
+    moving: Arc<AtomicBool>,
+    // Targets sent but not yet picked up by the actor
+    queued: Arc<AtomicUsize>,
+}
+
+impl Fader {
+    pub fn spawn(
+        log: Logger,
+        path: PathBuf,
+        last_written: LastWritten,
+        timing: TransitionTiming,
+        mode: MappingMode,
+        max_brightness: i32,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let target = Arc::new(AtomicI32::new(last_written.get()));
+        let moving = Arc::new(AtomicBool::new(false));
+        let queued = Arc::new(AtomicUsize::new(0));
+        let actor_queued = Arc::clone(&queued);
+
+        let mut actor = FadeActor {
+            log,
+            path,
+            last_written,
+            target: Arc::clone(&target),
+            moving: Arc::clone(&moving),
+            timing,
+            mode,
+            max_brightness,
+        };
+        let handle = thread::spawn(move || {
+            let mut segment: Option<Segment> = None;
+            let mut frame = 0u32;
+            loop {
+                // Block while idle, otherwise wake up for the next frame
+                let command = match &segment {
+                    None => match rx.recv() {
+                        Ok(command) => Some(command),
+                        Err(_) => break,
+                    },
+                    Some(seg) => {
+                        let deadline = seg.start + actor.timing.frame * frame;
+                        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                            Ok(command) => Some(command),
+                            Err(RecvTimeoutError::Timeout) => None,
+                            Err(RecvTimeoutError::Disconnected) => break,
+                        }
+                    }
+                };
+
+                match command {
+                    Some(FadeCommand::Stop) => break,
+                    Some(FadeCommand::Configure { timing, mode }) => {
+                        actor.timing = timing;
+                        actor.mode = mode;
+                    }
+                    Some(FadeCommand::Target { value, instant }) => {
+                        let unchanged = segment.as_ref().is_some_and(|seg| seg.to == value);
+                        if instant || !unchanged {
+                            segment = actor.retarget(segment.as_ref(), value, instant);
+                            frame = 0;
+                        }
+                        actor_queued.fetch_sub(1, Ordering::SeqCst);
+                    }
+                    None => {}
+                }
+
+                if let Some(seg) = &segment {
+                    match actor.step(seg) {
+                        Some(next) => frame = frame.max(next),
+                        None => segment = None,
+                    }
+                }
+            }
+            actor.moving.store(false, Ordering::SeqCst);
+        });
+
+        Self {
+            tx,
+            handle: Some(handle),
+            target,
+            moving,
+            queued,
+        }
+    }
+
+    // Head for `value`, jumping straight there when `instant`
+    pub fn set_target(&self, value: i32, instant: bool) {
+        self.target.store(value, Ordering::SeqCst);
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        if self
+            .tx
+            .send(FadeCommand::Target { value, instant })
+            .is_err()
+        {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    // Use `timing` and `mode` for transitions from now on
+    pub fn configure(&self, timing: TransitionTiming, mode: MappingMode) {
+        let _ = self.tx.send(FadeCommand::Configure { timing, mode });
+    }
+
+    // Brightness the output is at or heading for
+    pub fn target(&self) -> i32 {
+        self.target.load(Ordering::SeqCst)
+    }
+
+    // Accept a brightness set outside lumd as the new resting point
+    pub fn adopt(&self, value: i32) {
+        self.target.store(value, Ordering::SeqCst);
+    }
+
+    // True while a fade runs or a target is waiting for the actor to pick it up
+    pub fn is_moving(&self) -> bool {
+        self.moving.load(Ordering::SeqCst) || self.queued.load(Ordering::SeqCst) > 0
+    }
+
+    // Abandon any running fade and wait for the actor to exit
+    pub fn stop(&mut self) {
+        let _ = self.tx.send(FadeCommand::Stop);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+struct FadeActor {
+    log: Logger,
+    path: PathBuf,
+    // Shared with the external change watcher
+    last_written: LastWritten,
+    target: Arc<AtomicI32>,
+    moving: Arc<AtomicBool>,
+    timing: TransitionTiming,
+    mode: MappingMode,
+    max_brightness: i32,
+}
+
+impl FadeActor {
+    fn write(&self, value: i32) {
+        if let Err(e) = self.last_written.write(&self.path, value) {
+            error!(self.log, "Failed to set brightness"; "error" => %e);
+        }
+    }
+
+    // Start a new leg from the brightness reached so far
+    fn retarget(&self, current: Option<&Segment>, value: i32, instant: bool) -> Option<Segment> {
+        let written = self.last_written.get();
+        if instant || value == written && current.is_none() {
+            if value != written {
+                self.write(value);
+            }
+            self.moving.store(false, Ordering::SeqCst);
+            return None;
+        }
+
+        let from = match current {
+            Some(seg) => self.position(seg, Instant::now()),
+            None => written as f32,
+        };
+        debug!(self.log, "Fading to new target";
+            "from" => format!("{:.1}", from),
+            "to" => value,
+            "retarget" => current.is_some());
+        self.moving.store(true, Ordering::SeqCst);
+        Some(Segment {
+            from,
+            to: value,
+            start: Instant::now(),
+            duration: self.timing.duration_for(from, value, self.max_brightness),
+        })
+    }
+
+    // Unrounded brightness along a segment at `now`
+    fn position(&self, seg: &Segment, now: Instant) -> f32 {
+        let elapsed = now.saturating_duration_since(seg.start);
+        if elapsed >= seg.duration {
+            return seg.to as f32;
+        }
+        let t = match &self.timing.adaptation {
+            Some(adaptation) => adaptation.progress(seg.from, seg.to as f32, elapsed),
+            None => self
+                .timing
+                .easing
+                .apply(elapsed.as_secs_f32() / seg.duration.as_secs_f32()),
+        };
+        self.mode
+            .interpolate(seg.from, seg.to as f32, t, self.max_brightness)
+    }
+
+    // Write the brightness due now, returning the next frame or None when done
+    fn step(&self, seg: &Segment) -> Option<u32> {
+        let now = Instant::now();
+        let done = now.saturating_duration_since(seg.start) >= seg.duration;
+        let value = self.position(seg, now).round() as i32;
+        match self.last_written.write_unless_changed(&self.path, value) {
+            // Someone else set the brightness mid-fade, leave it to them
+            Ok(Some(current)) => {
+                info!(self.log, "Brightness changed externally, stopping transition";
+                    "written" => self.last_written.get(),
+                    "current" => current);
+                self.target.store(current, Ordering::SeqCst);
+                self.moving.store(false, Ordering::SeqCst);
+                return None;
+            }
+            Ok(None) => {}
+            Err(e) => error!(self.log, "Failed to set brightness"; "error" => %e),
+        }
+        if done {
+            self.moving.store(false, Ordering::SeqCst);
+            return None;
+        }
+
+        // Skip frames a slow write overran
+        let elapsed = seg.start.elapsed();
+        Some((elapsed.as_nanos() / self.timing.frame.as_nanos()) as u32 + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    // A fake backlight directory holding only a `brightness` file
+    fn backlight(name: &str, value: i32) -> TempDir {
+        let dir = TempDir::new(name);
+        set_brightness(&dir, value).unwrap();
+        dir
+    }
+
+    fn timing(easing: Easing, scale_by_distance: bool) -> TransitionTiming {
+        TransitionTiming {
+            duration: Duration::from_millis(1000),
+            frame: Duration::from_millis(20),
+            easing,
+            scale_by_distance,
+            adaptation: None,
+        }
+    }
+
+    #[test]
+    fn easings_are_monotonic_from_zero_to_one() {
+        for easing in [Easing::Linear, Easing::EaseInOut, Easing::Exponential] {
+            assert_eq!(easing.apply(0.0), 0.0, "{}", easing.name());
+            assert!((easing.apply(1.0) - 1.0).abs() < 1e-6, "{}", easing.name());
+            let mut previous = 0.0;
+            for step in 1..=100 {
+                let value = easing.apply(step as f32 / 100.0);
+                assert!(value >= previous, "{} at step {}", easing.name(), step);
+                previous = value;
+            }
+            // Out-of-range times are clamped
+            assert_eq!(easing.apply(-1.0), 0.0);
+            assert!((easing.apply(2.0) - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn duration_scales_with_distance() {
+        let fixed = timing(Easing::Linear, false);
+        assert_eq!(
+            fixed.duration_for(0.0, 10, 1000),
+            Duration::from_millis(1000)
+        );
+
+        let scaled = timing(Easing::Linear, true);
+        assert_eq!(
+            scaled.duration_for(0.0, 500, 1000),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            scaled.duration_for(1000.0, 0, 1000),
+            Duration::from_millis(1000)
+        );
+        // Even a tiny change takes at least one frame
+        assert_eq!(
+            scaled.duration_for(500.0, 501, 1000),
+            Duration::from_millis(20)
+        );
+    }
+
+    #[test]
+    fn adaptation_brightens_faster_than_it_dims() {
+        let adaptation = AdaptationConfig {
+            enabled: true,
+            ..AdaptationConfig::default()
+        };
+        let rising = adaptation.duration(100.0, 900.0);
+        let falling = adaptation.duration(900.0, 100.0);
+        assert!(rising < falling);
+        assert!((falling.as_secs_f32() / rising.as_secs_f32() - 20.0).abs() < 1e-3);
+
+        // Once the duration is up the output is within half a raw step of the target
+        let progress = adaptation.progress(100.0, 900.0, rising);
+        assert!((800.0 * (1.0 - progress) - 0.5).abs() < 0.05);
+        assert!(adaptation.progress(900.0, 100.0, rising) < 0.5);
+
+        let timing = TransitionTiming {
+            adaptation: Some(adaptation),
+            ..timing(Easing::Linear, true)
+        };
+        assert_eq!(timing.duration_for(100.0, 900, 1000), rising);
+    }
+
+    #[test]
+    fn external_write_is_not_overwritten() {
+        let path = backlight("external", 100);
+        let written = LastWritten::new(100);
+        assert_eq!(written.write_unless_changed(&path, 120).unwrap(), None);
+        assert_eq!(written.get(), 120);
+
+        // Someone else sets 30; lumd's next frame must not clobber it
+        set_brightness(&path, 30).unwrap();
+        assert!(written.differs(&path).unwrap());
+        assert_eq!(written.write_unless_changed(&path, 140).unwrap(), Some(30));
+        assert_eq!(read_brightness(&path).unwrap(), 30);
+
+        assert_eq!(written.adopt_external(&path).unwrap(), Some((120, 30)));
+        assert_eq!(written.get(), 30);
+        assert_eq!(written.adopt_external(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn queued_target_counts_as_moving() {
+        let path = backlight("queued", 100);
+        let written = LastWritten::new(100);
+        let timing = TransitionTiming {
+            duration: Duration::ZERO,
+            frame: Duration::from_millis(1),
+            ..timing(Easing::Linear, false)
+        };
+        let log = Logger::root(slog::Discard, slog::o!());
+        let mut fader = Fader::spawn(
+            log,
+            path.to_path_buf(),
+            written.clone(),
+            timing,
+            MappingMode::Linear,
+            1000,
+        );
+
+        fader.set_target(400, true);
+        // Until the actor has written it, the change is lumd's own
+        assert!(fader.is_moving() || written.get() == 400);
+        fader.stop();
+        assert_eq!(written.get(), 400);
+        assert!(!fader.is_moving());
+        assert!(!written.differs(&path).unwrap());
+    }
+
+    #[test]
+    fn resync_rewrites_a_target_matching_the_old_value() {
+        let path = backlight("resync", 400);
+        let written = LastWritten::new(400);
+        let timing = TransitionTiming {
+            duration: Duration::ZERO,
+            frame: Duration::from_millis(1),
+            ..timing(Easing::Linear, false)
+        };
+        let log = Logger::root(slog::Discard, slog::o!());
+        let mut fader = Fader::spawn(
+            log,
+            path.to_path_buf(),
+            written.clone(),
+            timing,
+            MappingMode::Linear,
+            1000,
+        );
+
+        // The driver restored something else while the panel was off
+        set_brightness(&path, 50).unwrap();
+        assert_eq!(written.resync(&path).unwrap(), 50);
+        fader.set_target(400, true);
+        fader.stop();
+        assert_eq!(read_brightness(&path).unwrap(), 400);
+    }
+}